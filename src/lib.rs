@@ -1 +1,2 @@
 pub mod liquidity_pool;
+pub mod ring_buffer;
@@ -1,13 +1,40 @@
-use std::fmt::Error;
-
+use std::collections::HashMap;
 use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ring_buffer::RingBuffer;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LpPoolError {
     InvalidFee,
     InsufficientLiquidity,
     InsufficientStakedTokens,
     InvalidTokenAmount,
+    InvalidThreshold,
+    InvalidConfig,
+    InvalidPrice,
+    PriceDeviation {
+        configured: f64,
+        observed: f64,
+    },
+    PoolNotYetActive,
+    InsufficientData,
+    InvalidInstruction,
+    LowOracleConfidence {
+        confidence: f64,
+        price: f64,
+    },
+    ArithmeticOverflow,
+    SlippageExceeded {
+        min_tokens_out: f64,
+        tokens_out: f64,
+    },
+    NotAuthorized,
+    PoolPaused,
 }
 
 impl fmt::Display for LpPoolError {
@@ -19,39 +46,436 @@ impl fmt::Display for LpPoolError {
                 write!(f, "Insufficient staked tokens in the pool.")
             }
             LpPoolError::InvalidTokenAmount => write!(f, "Invalid token amount provided."),
+            LpPoolError::InvalidThreshold => write!(f, "Invalid threshold value provided."),
+            LpPoolError::InvalidConfig => write!(f, "Invalid pool configuration provided."),
+            LpPoolError::InvalidPrice => write!(f, "Invalid price provided."),
+            LpPoolError::PriceDeviation {
+                configured,
+                observed,
+            } => write!(
+                f,
+                "Pool price {} deviates from the configured price {}.",
+                observed, configured
+            ),
+            LpPoolError::PoolNotYetActive => write!(f, "Pool is not yet active for swaps."),
+            LpPoolError::InsufficientData => write!(f, "Insufficient data in the pool history."),
+            LpPoolError::InvalidInstruction => write!(f, "Invalid instruction data provided."),
+            LpPoolError::LowOracleConfidence { confidence, price } => write!(
+                f,
+                "Oracle confidence {} is too wide for the price {}.",
+                confidence, price
+            ),
+            LpPoolError::ArithmeticOverflow => {
+                write!(f, "Arithmetic overflow in a pool operation.")
+            }
+            LpPoolError::SlippageExceeded {
+                min_tokens_out,
+                tokens_out,
+            } => write!(
+                f,
+                "Swap would return {} tokens, less than the minimum of {}.",
+                tokens_out, min_tokens_out
+            ),
+            LpPoolError::NotAuthorized => write!(f, "Caller is not authorized."),
+            LpPoolError::PoolPaused => write!(f, "Pool is paused."),
         }
     }
 }
 
 impl std::error::Error for LpPoolError {}
 
+impl From<LpPoolError> for std::io::Error {
+    fn from(error: LpPoolError) -> Self {
+        let kind = match error {
+            LpPoolError::InvalidFee
+            | LpPoolError::InvalidTokenAmount
+            | LpPoolError::InvalidThreshold
+            | LpPoolError::InvalidPrice => std::io::ErrorKind::InvalidInput,
+            LpPoolError::InvalidConfig
+            | LpPoolError::PriceDeviation { .. }
+            | LpPoolError::InvalidInstruction
+            | LpPoolError::LowOracleConfidence { .. } => std::io::ErrorKind::InvalidData,
+            LpPoolError::InsufficientLiquidity
+            | LpPoolError::InsufficientStakedTokens
+            | LpPoolError::PoolNotYetActive
+            | LpPoolError::PoolPaused
+            | LpPoolError::InsufficientData
+            | LpPoolError::ArithmeticOverflow
+            | LpPoolError::SlippageExceeded { .. } => std::io::ErrorKind::Other,
+            LpPoolError::NotAuthorized => std::io::ErrorKind::PermissionDenied,
+        };
+        std::io::Error::new(kind, error)
+    }
+}
+
 /// Represents an amount of tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct TokenAmount(pub u64);
 
 /// Represents an amount of staked tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct StakedTokenAmount(pub u64);
 
 /// Represents an amount of LP tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct LpTokenAmount(pub u64);
 
 /// Represents the price of a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Price(pub u64);
 
 /// Represents a percentage value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Percentage(pub u64);
 
-/// Represents the precision factor used for decimal shifting.
-const PRECISION_FACTOR: u64 = 1_000_000_u64;
+/// Implements `Add`, `Sub`, `Mul<u64>` and `Div<u64>` for a raw amount newtype, panicking
+/// on overflow like the integer operators do, plus checked and saturating addition and
+/// subtraction.
+macro_rules! impl_raw_arithmetic {
+    ($($name:ident),*) => {$(
+        impl std::ops::Add for $name {
+            type Output = $name;
+
+            fn add(self, rhs: $name) -> $name {
+                $name(self.0.checked_add(rhs.0).unwrap_or_else(|| {
+                    panic!(concat!("attempt to add ", stringify!($name), "s with overflow"))
+                }))
+            }
+        }
+
+        impl std::ops::Sub for $name {
+            type Output = $name;
+
+            fn sub(self, rhs: $name) -> $name {
+                $name(self.0.checked_sub(rhs.0).unwrap_or_else(|| {
+                    panic!(concat!("attempt to subtract ", stringify!($name), "s with overflow"))
+                }))
+            }
+        }
+
+        impl std::ops::Mul<u64> for $name {
+            type Output = $name;
+
+            fn mul(self, rhs: u64) -> $name {
+                $name(self.0.checked_mul(rhs).unwrap_or_else(|| {
+                    panic!(concat!("attempt to multiply ", stringify!($name), " with overflow"))
+                }))
+            }
+        }
+
+        impl std::ops::Div<u64> for $name {
+            type Output = $name;
+
+            fn div(self, rhs: u64) -> $name {
+                $name(self.0.checked_div(rhs).unwrap_or_else(|| {
+                    panic!(concat!("attempt to divide ", stringify!($name), " by zero"))
+                }))
+            }
+        }
+
+        impl $name {
+            /// Adds `rhs`, returning `None` instead of overflowing.
+            pub fn checked_add(self, rhs: $name) -> Option<$name> {
+                self.0.checked_add(rhs.0).map($name)
+            }
+
+            /// Subtracts `rhs`, returning `None` instead of overflowing.
+            pub fn checked_sub(self, rhs: $name) -> Option<$name> {
+                self.0.checked_sub(rhs.0).map($name)
+            }
+
+            /// Adds `rhs`, saturating at `u64::MAX` raw units instead of overflowing.
+            pub fn saturating_add(self, rhs: $name) -> $name {
+                $name(self.0.saturating_add(rhs.0))
+            }
+
+            /// Subtracts `rhs`, saturating at zero instead of overflowing.
+            pub fn saturating_sub(self, rhs: $name) -> $name {
+                $name(self.0.saturating_sub(rhs.0))
+            }
+        }
+    )*};
+}
+
+impl_raw_arithmetic!(
+    TokenAmount,
+    StakedTokenAmount,
+    LpTokenAmount,
+    Price,
+    Percentage
+);
+
+/// Represents an inconsistency in the pool state found by `check_invariants`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvariantViolation {
+    /// The price is zero, so staked tokens are worthless and reverse swaps are undefined.
+    ZeroPrice,
+    /// Reserves are held while no LP tokens are outstanding to claim them.
+    OrphanedReserves { tvl: u128 },
+    /// More raw LP tokens are outstanding than the raw value of the reserves backing them.
+    /// LP tokens are minted at most 1:1 against value and fees only add value, so this takes
+    /// a loss, e.g. from a price drop.
+    LpSupplyExceedsTvl { lp_supply: u64, tvl: u128 },
+    /// The raw fees are not ordered or exceed 100%.
+    FeeOutOfRange { min_fee: u64, max_fee: u64 },
+    /// The liquidity target is zero, leaving the fee curve undefined.
+    ZeroLiquidityTarget,
+    /// The rebalance threshold lies outside `(0, 0.5)`.
+    RebalanceThresholdOutOfRange { threshold: f64 },
+}
+
+/// Represents the shape of the fee curve between `max_fee` at an empty token reserve and
+/// `min_fee` at the liquidity target. With `x = amount_after / liquidity_target`:
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FeeModel {
+    /// fee = max_fee - (max_fee - min_fee) * x
+    Linear,
+    /// fee = min_fee + (max_fee - min_fee) * (e^(k * (1 - x)) - 1) / (e^k - 1), convex in the
+    /// depletion `1 - x`, with the raw fixed-point `exponent` k > 0.
+    Exponential { exponent: u64 },
+}
+
+impl FeeModel {
+    /// Creates an exponential fee model with the curvature `exponent`.
+    pub fn exponential(exponent: f64) -> Self {
+        FeeModel::Exponential {
+            exponent: (exponent * DEFAULT_PRECISION_FACTOR as f64).round() as u64,
+        }
+    }
+}
+
+/// Represents a pool operation encoded into a transaction instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PoolOp {
+    AddLiquidity(f64),
+    Swap(f64),
+    RemoveLiquidity(f64),
+}
+
+/// Represents the direction of a swap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapDirection {
+    StakedToToken,
+    TokenToStaked,
+}
+
+/// Represents the effect of a liquidity withdrawal on the pool.
+pub struct ExitImpact {
+    /// The total value locked after the withdrawal, in tokens.
+    pub new_tvl: f64,
+    /// The fee percentage a marginal swap pays after the withdrawal.
+    pub new_fee_at_utilization: f64,
+    /// The change of the utilization ratio caused by the withdrawal.
+    pub utilization_change: f64,
+    /// The fraction of the LP token supply being withdrawn.
+    pub lp_fraction_exiting: f64,
+}
+
+/// Represents the pool reserves recorded at a point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReserveSnapshot {
+    pub timestamp: u64,
+    pub token: u64,
+    pub st_token: u64,
+    pub price: u64,
+}
+
+/// Represents a swap recorded in the pool history.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SwapRecord {
+    /// The unix timestamp in seconds of the swap.
+    pub timestamp: u64,
+    /// The raw execution price, tokens received per staked token.
+    pub price: u64,
+    /// The raw value of the swapped staked tokens in tokens.
+    pub volume_usd: u64,
+}
+
+/// Represents the pool value and LP token supply recorded at a point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolSnapshot {
+    /// The unix timestamp in seconds of the snapshot.
+    pub timestamp: u64,
+    /// The LP token supply.
+    pub lp_supply: f64,
+    /// The total value locked in tokens.
+    pub tvl: f64,
+}
+
+/// Represents the pool state a swap changes, saved to revert a failed `batch_swap`.
+struct LpPoolSnapshot {
+    token_amount: TokenAmount,
+    st_token_amount: StakedTokenAmount,
+    lp_token_amount: LpTokenAmount,
+    cumulative_fee_token: TokenAmount,
+    protocol_fee_accrued: TokenAmount,
+    total_volume_swapped: u64,
+    total_fees_collected: u64,
+    reserve_ring: RingBuffer<ReserveSnapshot, RESERVE_RING_SIZE>,
+    swap_history: RingBuffer<SwapRecord, SWAP_HISTORY_SIZE>,
+}
+
+/// Represents a liquidity provider's position opened with `add_liquidity`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LiquidityPosition {
+    /// The amount of LP tokens held.
+    pub lp_amount: f64,
+    /// The amount of tokens deposited.
+    pub deposit_value: f64,
+    /// The pool price at the time of the deposit.
+    pub deposit_price: f64,
+    /// The unix timestamp in seconds of the deposit.
+    pub deposit_time: u64,
+    /// The pool's `cumulative_fee_token` at the time of the deposit, in tokens.
+    pub deposit_cumulative_fee: f64,
+}
+
+/// Represents the profit and loss of a liquidity position, in tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PositionPnl {
+    pub unrealized_gain: f64,
+    pub fee_earnings: f64,
+    pub impermanent_loss: f64,
+    pub net_pnl: f64,
+    pub holding_days: f64,
+}
+
+/// Represents the outcome of a prospective swap, computed without changing the pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SwapEstimate {
+    /// The fee charged as a fraction of the swapped value.
+    pub fee_rate: f64,
+    /// The amount received: tokens for `estimate_swap`, staked tokens for
+    /// `estimate_swap_tokens_for_staked`.
+    pub tokens_out: f64,
+    /// The shortfall of the output against the spot price, in basis points.
+    pub price_impact_bps: u64,
+    /// The execution price in tokens per staked token.
+    pub effective_price: f64,
+}
+
+/// Represents a pool price update made with `update_price`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PriceImpact {
+    /// The price before the update, in tokens per staked token.
+    pub old_price: f64,
+    /// The price after the update, in tokens per staked token.
+    pub new_price: f64,
+    /// The profit in tokens, before fees, an arbitrageur could have made by trading the
+    /// reserves at the old price against the new one.
+    pub implied_arb_opportunity: f64,
+}
+
+/// Represents aggregate pool metrics for display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolStats {
+    /// The total value locked in tokens, `token_amount + st_token_amount * price`.
+    pub tvl_tokens: f64,
+    /// The shortfall of the token reserve below the liquidity target as a fraction of it,
+    /// clamped to `[0, 1]`, i.e. `1 - utilization_ratio()`.
+    pub depletion_ratio: f64,
+    /// The fee rate a marginal swap pays at the current depth.
+    pub current_fee_rate: f64,
+    /// The value of one LP token in tokens.
+    pub lp_token_price: f64,
+    /// The value in tokens of everything swapped since the pool was created.
+    pub cumulative_volume: f64,
+    /// The swap fees charged since the pool was created, in tokens.
+    pub cumulative_fees: f64,
+}
+
+/// Represents the worst-case liquidity provider loss within a price range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaxLossScenario {
+    /// The price at which the pool value is lowest.
+    pub worst_price: f64,
+    /// The largest impermanent loss as a fraction of the held value.
+    pub max_il: f64,
+    /// The price at which the impermanent loss is largest.
+    pub max_il_price: f64,
+    /// The pool value in tokens at `worst_price`.
+    pub pool_value_at_worst: f64,
+}
+
+/// Represents the default precision factor used for decimal shifting, six decimal places.
+pub const DEFAULT_PRECISION_FACTOR: u64 = 1_000_000_u64;
+
+/// Represents the smallest precision factor a pool accepts.
+const MIN_PRECISION_FACTOR: u64 = 256;
+
+/// Represents the minimum raw value the pool has to hold for swaps and withdrawals.
+const MINIMUM_LIQUIDITY: u64 = 1_000;
+
+/// Represents the maximum referral fee in basis points (5%).
+const MAX_REFERRER_FEE_BPS: u16 = 500;
+
+/// Represents the approximate block time in seconds used for the anti-snipe delay.
+const BLOCK_TIME_SECONDS: u64 = 1;
+
+/// Represents the size in bytes of the serialized pool account data.
+const ACCOUNT_DATA_SIZE: usize = 256;
+
+/// Represents the account discriminator identifying serialized pool data.
+const ACCOUNT_DISCRIMINATOR: u8 = 0x4c;
+
+/// Represents the version of the serialized pool account layout.
+const ACCOUNT_VERSION: u8 = 1;
+
+/// Represents the number of hourly reserve snapshots kept by the pool.
+const RESERVE_RING_SIZE: usize = 24;
+
+/// Represents the number of swaps kept in the pool history.
+const SWAP_HISTORY_SIZE: usize = 256;
+
+/// Represents the number of price samples used by `maximum_loss_scenario`.
+const LOSS_SCENARIO_SAMPLES: usize = 1_000;
+
+/// Represents the number of seconds in an hour.
+const SECONDS_PER_HOUR: u64 = 3_600;
+
+/// Represents the number of seconds in a day.
+const SECONDS_PER_DAY: u64 = 86_400;
 
 /// Represents a liquidity pool with various parameters.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LpPool {
+    pub precision_factor: u64,
     pub price: Price,
     pub token_amount: TokenAmount,
     pub st_token_amount: StakedTokenAmount,
     pub lp_token_amount: LpTokenAmount,
+    pub locked_lp_tokens: LpTokenAmount,
     pub liquidity_target: TokenAmount,
     pub min_fee: Percentage,
     pub max_fee: Percentage,
+    pub fee_model: FeeModel,
+    pub protocol_fee_share: Percentage,
+    pub protocol_fee_accrued: TokenAmount,
+    pub rebalance_threshold: Option<f64>,
+    pub reserve_ring: RingBuffer<ReserveSnapshot, RESERVE_RING_SIZE>,
+    pub max_price_deviation_bps: Option<u64>,
+    pub cumulative_fee_token: TokenAmount,
+    pub total_volume_swapped: u64,
+    pub total_fees_collected: u64,
+    pub launch_timestamp: u64,
+    pub anti_snipe_blocks: u64,
+    pub paused: bool,
+    pub swap_history: RingBuffer<SwapRecord, SWAP_HISTORY_SIZE>,
+    pub processed_nonces: HashMap<u64, f64>,
 }
 
 impl LpPool {
@@ -63,6 +487,10 @@ impl LpPool {
     /// * `liquidity_target` - The target amount of liquidity for the pool.
     /// * `min_fee` - The minimum fee percentage.
     /// * `max_fee` - The maximum fee percentage.
+    /// * `fee_model` - The shape of the fee curve between `max_fee` and `min_fee`.
+    /// * `protocol_fee_share` - The percentage of each swap fee accrued to the treasury.
+    /// * `precision_factor` - The raw units per token, a power of two or ten of at least 256,
+    ///   usually `DEFAULT_PRECISION_FACTOR`.
     ///
     ///
     /// Calculates :
@@ -77,38 +505,113 @@ impl LpPool {
         liquidity_target: f64,
         min_fee: f64,
         max_fee: f64,
+        fee_model: FeeModel,
+        protocol_fee_share: f64,
+        precision_factor: u64,
+    ) -> Result<Self, LpPoolError> {
+        Self::init_with_anti_snipe(
+            price,
+            liquidity_target,
+            min_fee,
+            max_fee,
+            fee_model,
+            protocol_fee_share,
+            precision_factor,
+            0,
+        )
+    }
+
+    /// Initializes a new liquidity pool which only accepts swaps once `anti_snipe_blocks`
+    /// blocks have passed since the launch, keeping front-running bots out of a fresh pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `price` - The price of the token.
+    /// * `liquidity_target` - The target amount of liquidity for the pool.
+    /// * `min_fee` - The minimum fee percentage.
+    /// * `max_fee` - The maximum fee percentage.
+    /// * `fee_model` - The shape of the fee curve between `max_fee` and `min_fee`.
+    /// * `protocol_fee_share` - The percentage of each swap fee accrued to the treasury.
+    /// * `precision_factor` - The raw units per token, see `init`.
+    /// * `anti_snipe_blocks` - The number of blocks swaps are disabled for, `init` uses 0.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the initialized `LpPool` or an error.
+    #[allow(clippy::too_many_arguments)]
+    pub fn init_with_anti_snipe(
+        price: f64,
+        liquidity_target: f64,
+        min_fee: f64,
+        max_fee: f64,
+        fee_model: FeeModel,
+        protocol_fee_share: f64,
+        precision_factor: u64,
+        anti_snipe_blocks: u64,
     ) -> Result<Self, LpPoolError> {
+        if !Self::is_valid_precision_factor(precision_factor) {
+            return Err(LpPoolError::InvalidConfig);
+        }
         if max_fee > 100.0 || min_fee < 0.0 || (min_fee > max_fee) || liquidity_target <= 0.0 {
             return Err(LpPoolError::InvalidFee);
         }
+        if price.is_nan() || price <= 0.0 || price.is_infinite() {
+            return Err(LpPoolError::InvalidPrice);
+        }
+        if !(0.0..=100.0).contains(&protocol_fee_share) {
+            return Err(LpPoolError::InvalidFee);
+        }
+        if fee_model == (FeeModel::Exponential { exponent: 0 }) {
+            return Err(LpPoolError::InvalidFee);
+        }
         // decimal shifting to provide float-like precision
-        let price = Price((price * PRECISION_FACTOR as f64).round() as u64);
+        let price = Price((price * precision_factor as f64).round() as u64);
+        if price.0 == 0 {
+            return Err(LpPoolError::InvalidPrice);
+        }
         let liquidity_target =
-            TokenAmount((liquidity_target * PRECISION_FACTOR as f64).round() as u64);
-        let min_fee = Percentage((0.01 * min_fee * PRECISION_FACTOR as f64).round() as u64);
-        let max_fee = Percentage((0.01 * max_fee * PRECISION_FACTOR as f64).round() as u64);
+            TokenAmount((liquidity_target * precision_factor as f64).round() as u64);
+        let min_fee = Percentage((0.01 * min_fee * precision_factor as f64).round() as u64);
+        let max_fee = Percentage((0.01 * max_fee * precision_factor as f64).round() as u64);
+        let protocol_fee_share =
+            Percentage((0.01 * protocol_fee_share * precision_factor as f64).round() as u64);
 
-        let token_amount = TokenAmount(liquidity_target.0);
+        // the pool starts empty, the first deposit mints LP tokens 1:1 and locks
+        // MINIMUM_LIQUIDITY of them. Seeding the token reserve with `liquidity_target` would
+        // mint LP tokens nobody holds and dilute every later liquidity provider.
+        let token_amount = TokenAmount(0);
         let st_token_amount = StakedTokenAmount(0);
-        println!(
-            "token: {} \nst_token: {}",
-            token_amount.0 / PRECISION_FACTOR,
-            st_token_amount.0 / PRECISION_FACTOR
-        );
-        let lp_token_amount = LpTokenAmount(liquidity_target.0); //  1:1 for first transaction
+        let lp_token_amount = LpTokenAmount(0);
 
         Ok(LpPool {
+            precision_factor,
             price,
             token_amount,
             st_token_amount,
             lp_token_amount,
+            locked_lp_tokens: LpTokenAmount(0),
             liquidity_target,
             min_fee,
             max_fee,
+            fee_model,
+            protocol_fee_share,
+            protocol_fee_accrued: TokenAmount(0),
+            rebalance_threshold: None,
+            reserve_ring: RingBuffer::new(),
+            max_price_deviation_bps: None,
+            cumulative_fee_token: TokenAmount(0),
+            total_volume_swapped: 0,
+            total_fees_collected: 0,
+            launch_timestamp: Self::now(),
+            anti_snipe_blocks,
+            paused: false,
+            swap_history: RingBuffer::new(),
+            processed_nonces: HashMap::new(),
         })
     }
 
-    /// Adds liquidity to the pool.
+    /// Adds liquidity to the pool. Converts to and from raw amounts around
+    /// `add_liquidity_raw`.
     ///
     /// # Arguments
     ///
@@ -116,36 +619,146 @@ impl LpPool {
     ///
     /// # Returns
     ///
-    /// A result containing the amount of LP tokens received or an error.
+    /// A result containing the amount of LP tokens received, `LpPoolError::ArithmeticOverflow`
+    /// if a reserve or the LP supply would overflow, or another error.
     pub fn add_liquidity(&mut self, token_amount: f64) -> Result<f64, LpPoolError> {
-        let new_tokens_u64 = (token_amount * PRECISION_FACTOR as f64).round() as u64;
-
-        if token_amount <= 0.0 {
+        self.ensure_not_paused()?;
+        if token_amount.is_nan() || token_amount <= 0.0 {
             return Err(LpPoolError::InvalidTokenAmount);
         }
+        let lp_token_received = self.add_liquidity_raw(self.checked_raw(token_amount)?)?;
+
+        Ok(lp_token_received as f64 / self.precision_factor as f64)
+    }
 
-        // Split the added liquidity between token_amount
-        let tokens_to_add = new_tokens_u64;
+    /// Checks that `precision_factor` is a power of two of at least `MIN_PRECISION_FACTOR`.
+    /// `DEFAULT_PRECISION_FACTOR` is the only decimal factor accepted, as existing pools and
+    /// stored accounts rely on it.
+    fn is_valid_precision_factor(precision_factor: u64) -> bool {
+        precision_factor == DEFAULT_PRECISION_FACTOR
+            || (precision_factor >= MIN_PRECISION_FACTOR && precision_factor.is_power_of_two())
+    }
+
+    /// Adds liquidity to the pool. This is the canonical implementation behind
+    /// `add_liquidity`.
+    ///
+    /// Raw amounts are fixed-point: `precision_factor` raw units are one token, so with
+    /// `DEFAULT_PRECISION_FACTOR` `1_500_000` raw tokens are `1.5` tokens. No f64 is
+    /// involved, so equal inputs always give bit-exact equal results.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_tokens_u64` - The raw amount of tokens to add to the pool.
+    ///
+    /// The first deposit mints LP tokens 1:1 and permanently locks `MINIMUM_LIQUIDITY` of
+    /// them, so the pool can never be drained completely.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the raw amount of LP tokens received,
+    /// `LpPoolError::ArithmeticOverflow` if a reserve or the LP supply would overflow, or
+    /// another error.
+    pub fn add_liquidity_raw(&mut self, new_tokens_u64: u64) -> Result<u64, LpPoolError> {
+        self.ensure_not_paused()?;
+        if new_tokens_u64 == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
 
-        let token_sum = self.st_token_amount.0;
-        let lp_token_received = if token_sum > 0 {
+        let old_tvl = self.compute_tvl_raw();
+        let pool_value = self.pool_value();
+        let (lp_token_minted, lp_token_received) = if self.lp_token_amount.0 > 0 && pool_value > 0 {
             // Calculate LP tokens using u128 to prevent overflow
+            let lp_tokens = (new_tokens_u64 as u128 * self.lp_token_amount.0 as u128) / pool_value;
             let lp_tokens =
-                (new_tokens_u64 as u128 * self.lp_token_amount.0 as u128) / token_sum as u128;
-            lp_tokens as u64
+                u64::try_from(lp_tokens).map_err(|_| LpPoolError::ArithmeticOverflow)?;
+            (lp_tokens, lp_tokens)
         } else {
-            // Initial liquidity
-            new_tokens_u64
+            // Initial liquidity, the locked part has to stay in the pool forever
+            if new_tokens_u64 <= MINIMUM_LIQUIDITY {
+                return Err(LpPoolError::InvalidTokenAmount);
+            }
+            (new_tokens_u64, new_tokens_u64 - MINIMUM_LIQUIDITY)
         };
-        self.token_amount.0 += tokens_to_add;
+        let token_amount = self
+            .token_amount
+            .checked_add(TokenAmount(new_tokens_u64))
+            .ok_or(LpPoolError::ArithmeticOverflow)?;
+        let lp_token_amount = self
+            .lp_token_amount
+            .checked_add(LpTokenAmount(lp_token_minted))
+            .ok_or(LpPoolError::ArithmeticOverflow)?;
+        self.token_amount = token_amount;
 
         // Issue LP tokens equivalent to the total added tokens
-        self.lp_token_amount.0 += lp_token_received;
+        self.lp_token_amount = lp_token_amount;
+        self.locked_lp_tokens = self.locked_lp_tokens
+            + (LpTokenAmount(lp_token_minted) - LpTokenAmount(lp_token_received));
+        self.record_reserve_snapshot(Self::now());
+        self.debug_assert_tvl(old_tvl, 0);
+        self.debug_assert_invariants();
+
+        Ok(lp_token_received)
+    }
+
+    /// Adds liquidity to the pool with staked tokens only, valued at the pool price.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_amount` - The amount of staked tokens to add to the pool.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of LP tokens received, `LpPoolError::ArithmeticOverflow`
+    /// if a reserve or the LP supply would overflow, or another error.
+    pub fn add_liquidity_single_sided(&mut self, staked_amount: f64) -> Result<f64, LpPoolError> {
+        self.ensure_not_paused()?;
+        self.validate_swap_inputs(staked_amount)?;
+        let staked_raw = (staked_amount * self.precision_factor as f64).round() as u64;
+        let value_raw = staked_raw as u128 * self.price.0 as u128 / self.precision_factor as u128;
+
+        let old_tvl = self.compute_tvl_raw();
+        let pool_value = self.pool_value();
+        let lp_token_received = if self.lp_token_amount.0 > 0 && pool_value > 0 {
+            value_raw
+                .checked_mul(self.lp_token_amount.0 as u128)
+                .ok_or(LpPoolError::ArithmeticOverflow)?
+                / pool_value
+        } else {
+            value_raw
+        };
+        let lp_token_minted =
+            u64::try_from(lp_token_received).map_err(|_| LpPoolError::ArithmeticOverflow)?;
+        // the first deposit locks MINIMUM_LIQUIDITY, see add_liquidity_raw
+        let lp_token_locked = if self.lp_token_amount.0 > 0 && pool_value > 0 {
+            0
+        } else {
+            MINIMUM_LIQUIDITY
+        };
+        if lp_token_minted <= lp_token_locked {
+            // the deposit is worth less than one raw LP token
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        let lp_token_received = lp_token_minted - lp_token_locked;
+        let st_token_amount = self
+            .st_token_amount
+            .checked_add(StakedTokenAmount(staked_raw))
+            .ok_or(LpPoolError::ArithmeticOverflow)?;
+        let lp_token_amount = self
+            .lp_token_amount
+            .checked_add(LpTokenAmount(lp_token_minted))
+            .ok_or(LpPoolError::ArithmeticOverflow)?;
+        self.st_token_amount = st_token_amount;
+        self.lp_token_amount = lp_token_amount;
+        self.locked_lp_tokens = self.locked_lp_tokens + LpTokenAmount(lp_token_locked);
+        self.record_reserve_snapshot(Self::now());
+        self.debug_assert_tvl(old_tvl, 0);
+        self.debug_assert_invariants();
 
-        Ok(lp_token_received as f64 / PRECISION_FACTOR as f64)
+        Ok(lp_token_received as f64 / self.precision_factor as f64)
     }
 
-    /// Removes liquidity from the pool.
+    /// Removes liquidity from the pool. Converts to and from raw amounts around
+    /// `remove_liquidity_raw`.
     ///
     /// # Arguments
     ///
@@ -155,149 +768,4929 @@ impl LpPool {
     ///
     /// A result containing a tuple with the amount of tokens and staked tokens received or an error.
     pub fn remove_liquidity(&mut self, lp_token_amount: f64) -> Result<(f64, f64), LpPoolError> {
-        let lp_token_amount_u64 = (lp_token_amount * PRECISION_FACTOR as f64).round() as u64;
-        let unstake_fee = self.max_fee.0
-            - (self.max_fee.0 - self.min_fee.0) * lp_token_amount_u64 / self.liquidity_target.0;
-        if self.lp_token_amount.0 < lp_token_amount_u64 {
-            return Err(LpPoolError::InsufficientLiquidity);
+        self.ensure_not_paused()?;
+        if lp_token_amount.is_nan() || lp_token_amount <= 0.0 {
+            return Err(LpPoolError::InvalidTokenAmount);
         }
-        self.lp_token_amount.0 -= lp_token_amount_u64;
-
-        let tokens_received_u64 = lp_token_amount_u64; // Simplified logic
-        let staked_tokens_received_u64 = 0; // Simplified logic
+        let (tokens_received_u64, staked_tokens_received_u64) =
+            self.remove_liquidity_raw(self.checked_raw(lp_token_amount)?)?;
 
-        self.token_amount.0 -= tokens_received_u64;
-
-        let tokens_received = tokens_received_u64 as f64 / PRECISION_FACTOR as f64;
-        let staked_tokens_received = staked_tokens_received_u64 as f64 / PRECISION_FACTOR as f64;
+        let tokens_received = tokens_received_u64 as f64 / self.precision_factor as f64;
+        let staked_tokens_received =
+            staked_tokens_received_u64 as f64 / self.precision_factor as f64;
 
         Ok((tokens_received, staked_tokens_received))
     }
 
-    /// Swaps staked tokens for regular tokens.
+    /// Removes liquidity from the pool using raw fixed-point amounts, see `add_liquidity_raw`.
+    /// This is the canonical implementation behind `remove_liquidity`.
     ///
     /// # Arguments
     ///
-    /// * `staked_token_amount` - The amount of staked tokens to swap.
+    /// * `lp_token_amount_u64` - The raw amount of LP tokens to remove from the pool.
     ///
     /// # Returns
     ///
-    /// A result containing the amount of tokens received or an error.
-    /// Swaps staked tokens for regular tokens.
-    ///
-    /// # Arguments
+    /// A result containing a tuple with the raw amount of tokens and staked tokens received
+    /// or an error.
+    pub fn remove_liquidity_raw(
+        &mut self,
+        lp_token_amount_u64: u64,
+    ) -> Result<(u64, u64), LpPoolError> {
+        self.ensure_not_paused()?;
+        if lp_token_amount_u64 == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        self.enforce_min_liquidity_constant()?;
+        let (tokens_received_u64, staked_tokens_received_u64, _) =
+            self.removal_output(lp_token_amount_u64)?;
+        let old_tvl = self.compute_tvl_raw();
+        // removal_output keeps each payout within its reserve
+        self.lp_token_amount = self
+            .lp_token_amount
+            .saturating_sub(LpTokenAmount(lp_token_amount_u64));
+        self.token_amount = self
+            .token_amount
+            .saturating_sub(TokenAmount(tokens_received_u64));
+        self.st_token_amount = self
+            .st_token_amount
+            .saturating_sub(StakedTokenAmount(staked_tokens_received_u64));
+        self.record_reserve_snapshot(Self::now());
+        // one unit of slack for rounding the staked reserve value
+        let value_paid_out = tokens_received_u64 as u128
+            + staked_tokens_received_u64 as u128 * self.price.0 as u128
+                / self.precision_factor as u128;
+        self.debug_assert_tvl(old_tvl, value_paid_out + 1);
+        self.debug_assert_invariants();
+
+        Ok((tokens_received_u64, staked_tokens_received_u64))
+    }
+
+    /// Calculates what burning `lp_raw` LP tokens pays out: the same share of each reserve.
     ///
-    /// * `staked_token_amount` - The amount of staked tokens to swap.
+    /// The unstake fee is charged on the staked leg, follows the swap fee curve at the token
+    /// reserve left after the removal and stays in the pool for the remaining liquidity
+    /// providers. The last provider out pays none as only the locked LP tokens would receive it.
     ///
     /// # Returns
     ///
-    /// A result containing the amount of tokens received or an error.
-    pub fn swap(&mut self, staked_token_amount: f64) -> Result<f64, LpPoolError> {
-        if staked_token_amount <= 0.0 {
+    /// A result containing the raw tokens and staked tokens paid out and the raw fee in staked
+    /// tokens, `LpPoolError::InvalidTokenAmount` if `lp_raw` is 0, or
+    /// `LpPoolError::InsufficientLiquidity` if `lp_raw` exceeds the redeemable LP supply or a
+    /// partial removal would leave less than `MINIMUM_LIQUIDITY` in the pool.
+    fn removal_output(&self, lp_raw: u64) -> Result<(u64, u64, u64), LpPoolError> {
+        if lp_raw == 0 {
             return Err(LpPoolError::InvalidTokenAmount);
         }
-
-        let staked_token_u64 =
-            StakedTokenAmount((staked_token_amount * PRECISION_FACTOR as f64).round() as u64);
-
-        if self.token_amount.0 == 0 {
+        let redeemable_lp = self.redeemable_lp_supply();
+        if redeemable_lp < lp_raw {
             return Err(LpPoolError::InsufficientLiquidity);
         }
+        // the locked LP tokens stay outstanding, so their share of the reserves is never paid out
+        let lp_supply = self.lp_token_amount.0 as u128;
+        let tokens_out = (lp_raw as u128 * self.token_amount.0 as u128 / lp_supply) as u64;
+        let gross_staked_out = (lp_raw as u128 * self.st_token_amount.0 as u128 / lp_supply) as u64;
 
-        // Constant product formula: R_base * R_staked = constant
-        let old_product = self.token_amount.0 as u128 * self.st_token_amount.0 as u128;
-
-        self.st_token_amount.0 += staked_token_u64.0;
+        let unstake_fee = if lp_raw == redeemable_lp {
+            0
+        } else {
+            let fee_rate =
+                self.fee_for_amount_after(self.token_amount.0.saturating_sub(tokens_out));
+            (gross_staked_out as u128 * fee_rate.min(self.precision_factor) as u128
+                / self.precision_factor as u128) as u64
+        };
+        let staked_out = gross_staked_out.saturating_sub(unstake_fee);
 
-        // Calculate new token reserve after the swap
-        let new_token_amount = (old_product / self.st_token_amount.0 as u128) as u64;
+        if lp_raw < redeemable_lp {
+            let remaining_value = self.token_amount.0.saturating_sub(tokens_out) as u128
+                + self.st_token_amount.0.saturating_sub(staked_out) as u128 * self.price.0 as u128
+                    / self.precision_factor as u128;
+            if remaining_value < MINIMUM_LIQUIDITY as u128 {
+                return Err(LpPoolError::InsufficientLiquidity);
+            }
+        }
 
-        // Tokens received by the user (before fee)
-        let tokens_received = self.token_amount.0 - new_token_amount;
+        Ok((tokens_out, staked_out, unstake_fee))
+    }
 
-        println!("tokens to user: {}", tokens_received / PRECISION_FACTOR);
+    /// Calculates the raw LP supply that can be burned, i.e. all LP tokens but the locked ones.
+    fn redeemable_lp_supply(&self) -> u64 {
+        self.lp_token_amount.saturating_sub(self.locked_lp_tokens).0
+    }
 
-        // Apply fee: fee = max_fee - (max_fee - min_fee) * available_tokens / liquidity_target
-        let fee = (self.max_fee.0
-            - (self.max_fee.0 - self.min_fee.0) * new_token_amount / self.liquidity_target.0)
-            .clamp(self.min_fee.0, self.max_fee.0);
+    /// Swaps staked tokens for regular tokens, failing if fewer than `min_tokens_out` tokens
+    /// would be received.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_token_amount` - The amount of staked tokens to swap.
+    /// * `min_tokens_out` - The smallest acceptable amount of tokens received.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of tokens received, `LpPoolError::SlippageExceeded` if it
+    /// would fall below `min_tokens_out`, or another error.
+    pub fn swap(
+        &mut self,
+        staked_token_amount: f64,
+        min_tokens_out: f64,
+    ) -> Result<f64, LpPoolError> {
+        self.ensure_not_paused()?;
+        let expected_tokens_out = self.estimate_swap_output(staked_token_amount)?;
+        if expected_tokens_out < min_tokens_out {
+            return Err(LpPoolError::SlippageExceeded {
+                min_tokens_out,
+                tokens_out: expected_tokens_out,
+            });
+        }
 
-        let precision_adjusted_fee = PRECISION_FACTOR - fee;
+        self.swap_unchecked(staked_token_amount)
+    }
 
-        let net_tokens_received = ((tokens_received as u128 * precision_adjusted_fee as u128)
-            / PRECISION_FACTOR as u128) as u64;
+    /// Swaps staked tokens for regular tokens without a minimum output.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_token_amount` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of tokens received or an error.
+    pub fn swap_unchecked(&mut self, staked_token_amount: f64) -> Result<f64, LpPoolError> {
+        self.validate_swap_inputs(staked_token_amount)?;
 
-        self.token_amount.0 = new_token_amount;
+        let staked_token_u64 = (staked_token_amount * self.precision_factor as f64).round() as u64;
+        let net_tokens_received = self.swap_raw(staked_token_u64)?;
 
-        Ok(net_tokens_received as f64 / PRECISION_FACTOR as f64)
+        Ok(net_tokens_received as f64 / self.precision_factor as f64)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Swaps several amounts of staked tokens for regular tokens in order, each swap seeing the
+    /// reserves left by the previous one. Either every swap executes or none does.
+    ///
+    /// # Arguments
+    ///
+    /// * `amounts` - The amounts of staked tokens to swap.
+    /// * `min_total_out` - The smallest acceptable amount of tokens received by all swaps.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of tokens received by each swap,
+    /// `LpPoolError::SlippageExceeded` if their sum would fall below `min_total_out`, or the
+    /// error of the first failing swap.
+    pub fn batch_swap(
+        &mut self,
+        amounts: &[f64],
+        min_total_out: f64,
+    ) -> Result<Vec<f64>, LpPoolError> {
+        self.ensure_not_paused()?;
+        let snapshot = self.snapshot();
+        let outputs = match amounts
+            .iter()
+            .map(|&amount| self.swap_unchecked(amount))
+            .collect::<Result<Vec<f64>, LpPoolError>>()
+        {
+            Ok(outputs) => outputs,
+            Err(err) => {
+                self.restore(snapshot);
+                return Err(err);
+            }
+        };
 
-    fn setup_pool() -> LpPool {
-        LpPool::init(
-            1.5,  // price
-            90.0, // liquidity_target
-            0.1,  // min_fee
-            9.0,  // max_fee
-        )
-        .unwrap()
+        let total_out: f64 = outputs.iter().sum();
+        if total_out < min_total_out {
+            self.restore(snapshot);
+            return Err(LpPoolError::SlippageExceeded {
+                min_tokens_out: min_total_out,
+                tokens_out: total_out,
+            });
+        }
+
+        Ok(outputs)
     }
 
-    #[test]
-    #[rustfmt::skip]
-    fn test_init() {
-        let pool = setup_pool();
+    /// Lends `amount` tokens from the reserve to `callback`, which has to pay back
+    /// `amount * (1 + fee_rate)` tokens before the call returns. The fee accrues to
+    /// `protocol_fee_accrued`, anything paid back above it returns to the reserve.
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - The amount of tokens to lend.
+    /// * `fee_rate` - The fee as a fraction of `amount`.
+    /// * `callback` - Receives the lent tokens and returns the amount of tokens paid back.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating a repaid loan, or `LpPoolError::InsufficientLiquidity` with the pool
+    /// left unchanged if the reserve cannot cover the loan or the callback fails, panics or
+    /// pays back too little.
+    pub fn flash_loan<F>(
+        &mut self,
+        amount: f64,
+        fee_rate: f64,
+        callback: F,
+    ) -> Result<(), LpPoolError>
+    where
+        F: FnOnce(f64) -> Result<f64, Box<dyn std::error::Error>>,
+    {
+        self.ensure_not_paused()?;
+        if amount.is_nan() || amount <= 0.0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        if !fee_rate.is_finite() || fee_rate < 0.0 {
+            return Err(LpPoolError::InvalidFee);
+        }
+        let amount_raw = self.checked_raw(amount)?;
+        if amount_raw > self.token_amount.0 {
+            return Err(LpPoolError::InsufficientLiquidity);
+        }
+        let fee_raw = (amount_raw as f64 * fee_rate).round() as u64;
+        let amount_due = amount_raw
+            .checked_add(fee_raw)
+            .ok_or(LpPoolError::ArithmeticOverflow)?;
 
-        assert_eq!(pool.price.0, (1.5 * PRECISION_FACTOR as f64).round() as u64);
-        assert_eq!(pool.token_amount.0, (90.0 * PRECISION_FACTOR as f64).round() as u64);
-        assert_eq!(pool.st_token_amount.0, 0);
-                assert_eq!(pool.lp_token_amount.0, (90.0 * PRECISION_FACTOR as f64).round() as u64);
-        assert_eq!(pool.liquidity_target.0, (90.0 * PRECISION_FACTOR as f64).round() as u64);
-        assert_eq!(pool.min_fee.0, (0.1 * 0.01 * PRECISION_FACTOR as f64).round() as u64);
-        assert_eq!(pool.max_fee.0, (9.0 * 0.01 * PRECISION_FACTOR as f64).round() as u64);
-    }
+        let old_tvl = self.compute_tvl_raw();
+        let snapshot = self.snapshot();
+        self.token_amount = self.token_amount - TokenAmount(amount_raw);
+        let repaid = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(amount)));
+        let repaid_raw = match repaid {
+            Ok(Ok(repaid)) => self.checked_raw(repaid).ok(),
+            _ => None,
+        };
+        let repaid_raw = match repaid_raw {
+            Some(repaid_raw) if repaid_raw >= amount_due => repaid_raw,
+            _ => {
+                self.restore(snapshot);
+                return Err(LpPoolError::InsufficientLiquidity);
+            }
+        };
 
-    #[test]
-    fn test_add_liquidity() {
-        let mut pool = setup_pool();
+        // the loan was taken from the reserve, so paying it back cannot overflow
+        self.token_amount = self
+            .token_amount
+            .saturating_add(TokenAmount(repaid_raw - fee_raw));
+        self.protocol_fee_accrued = self
+            .protocol_fee_accrued
+            .saturating_add(TokenAmount(fee_raw));
+        self.debug_assert_tvl(old_tvl, 0);
+        self.debug_assert_invariants();
 
-        let lp_tokens = pool.add_liquidity(100.0).unwrap();
+        Ok(())
+    }
 
-        assert_eq!(lp_tokens, 100.0);
+    /// Saves the reserves, LP supply, fee and volume totals and histories a swap changes.
+    fn snapshot(&self) -> LpPoolSnapshot {
+        LpPoolSnapshot {
+            token_amount: self.token_amount,
+            st_token_amount: self.st_token_amount,
+            lp_token_amount: self.lp_token_amount,
+            cumulative_fee_token: self.cumulative_fee_token,
+            protocol_fee_accrued: self.protocol_fee_accrued,
+            total_volume_swapped: self.total_volume_swapped,
+            total_fees_collected: self.total_fees_collected,
+            reserve_ring: self.reserve_ring.clone(),
+            swap_history: self.swap_history.clone(),
+        }
     }
 
-    #[test]
-    fn test_swap_successful() {
-        let mut pool = setup_pool();
-        let _ = pool.add_liquidity(100.0);
+    /// Reverts the pool to a state saved by `snapshot`.
+    fn restore(&mut self, snap: LpPoolSnapshot) {
+        self.token_amount = snap.token_amount;
+        self.st_token_amount = snap.st_token_amount;
+        self.lp_token_amount = snap.lp_token_amount;
+        self.cumulative_fee_token = snap.cumulative_fee_token;
+        self.protocol_fee_accrued = snap.protocol_fee_accrued;
+        self.total_volume_swapped = snap.total_volume_swapped;
+        self.total_fees_collected = snap.total_fees_collected;
+        self.reserve_ring = snap.reserve_ring;
+        self.swap_history = snap.swap_history;
+    }
 
-        let staked_tokens_to_swap = 6.0;
-        let expected_tokens_received = 8.991; // Expected value based on pool's swap logic.
+    /// Swaps regular tokens for staked tokens, failing if fewer than `min_st_out` staked tokens
+    /// would be received.
+    ///
+    /// st_received = token_in / price * (1 - fee), with the fee taken from the same curve as
+    /// `swap` at the token reserve after the swap.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_amount` - The amount of tokens to swap.
+    /// * `min_st_out` - The smallest acceptable amount of staked tokens received.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of staked tokens received,
+    /// `LpPoolError::InsufficientStakedTokens` if the staked reserve cannot cover it,
+    /// `LpPoolError::SlippageExceeded` if it would fall below `min_st_out`, or another error.
+    pub fn swap_tokens_for_staked(
+        &mut self,
+        token_amount: f64,
+        min_st_out: f64,
+    ) -> Result<f64, LpPoolError> {
+        self.ensure_not_paused()?;
+        self.validate_swap_inputs(token_amount)?;
+        let token_raw = (token_amount * self.precision_factor as f64).round() as u64;
 
-        let result = pool.swap(staked_tokens_to_swap).unwrap();
+        self.anti_snipe_delay(Self::now())?;
+        self.enforce_min_liquidity_constant()?;
+        if let Some(max_deviation_bps) = self.max_price_deviation_bps {
+            self.internal_price_check(max_deviation_bps)?;
+        }
+        let (st_received, fee_tokens) = self.reverse_swap_output(token_raw)?;
+        let st_out = st_received as f64 / self.precision_factor as f64;
+        if st_out < min_st_out {
+            return Err(LpPoolError::SlippageExceeded {
+                min_tokens_out: min_st_out,
+                tokens_out: st_out,
+            });
+        }
 
-        assert_eq!(result, expected_tokens_received);
-        // assert!((result - expected_tokens_received).abs() < 0.001);
-    }
+        let old_tvl = self.compute_tvl_raw();
+        let protocol_fee = self.protocol_fee_part(fee_tokens);
+        // reverse_swap_output checked both reserves
+        self.token_amount = self.token_amount + TokenAmount(token_raw - protocol_fee);
+        self.st_token_amount = self.st_token_amount - StakedTokenAmount(st_received);
+        self.protocol_fee_accrued = self
+            .protocol_fee_accrued
+            .saturating_add(TokenAmount(protocol_fee));
+        self.cumulative_fee_token = self
+            .cumulative_fee_token
+            .saturating_add(TokenAmount(fee_tokens - protocol_fee));
+        self.record_swap_totals(token_raw, fee_tokens);
+        if st_received > 0 {
+            self.swap_history.push(SwapRecord {
+                timestamp: Self::now(),
+                price: (token_raw as u128 * self.precision_factor as u128 / st_received as u128)
+                    as u64,
+                volume_usd: token_raw,
+            });
+        }
+        self.debug_assert_tvl(old_tvl, 0);
+        self.debug_assert_invariants();
+
+        if let Some(threshold) = self.rebalance_threshold {
+            if (self.reserve_ratio() - 0.5).abs() > threshold {
+                self.rebalance()?;
+            }
+        }
+        self.record_reserve_snapshot(Self::now());
+
+        Ok(st_out)
+    }
+
+    /// Swaps staked tokens for regular tokens using raw fixed-point amounts only.
+    /// Kept for existing callers, it forwards to `swap_raw`.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_raw` - The raw amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the raw amount of tokens received or an error.
+    #[deprecated(note = "use swap_raw")]
+    pub fn gas_optimized_swap(&mut self, staked_raw: u64) -> Result<u64, LpPoolError> {
+        self.swap_raw(staked_raw)
+    }
+
+    /// Swaps staked tokens for regular tokens using raw fixed-point amounts, see
+    /// `add_liquidity_raw`. This is the canonical implementation behind `swap`.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_raw` - The raw amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the raw amount of tokens received or an error.
+    pub fn swap_raw(&mut self, staked_raw: u64) -> Result<u64, LpPoolError> {
+        self.ensure_not_paused()?;
+        self.anti_snipe_delay(Self::now())?;
+        self.enforce_min_liquidity_constant()?;
+        if let Some(max_deviation_bps) = self.max_price_deviation_bps {
+            self.internal_price_check(max_deviation_bps)?;
+        }
+        let (net_tokens_received, fee_tokens) = self.swap_output(staked_raw)?;
+        let st_token_amount = self
+            .st_token_amount
+            .checked_add(StakedTokenAmount(staked_raw))
+            .ok_or(LpPoolError::ArithmeticOverflow)?;
+
+        let protocol_fee = self.protocol_fee_part(fee_tokens);
+
+        let old_tvl = self.compute_tvl_raw();
+        // swap_output never pays out more than the token reserve
+        self.token_amount = self
+            .token_amount
+            .saturating_sub(TokenAmount(net_tokens_received) + TokenAmount(protocol_fee));
+        self.st_token_amount = st_token_amount;
+        self.protocol_fee_accrued = self
+            .protocol_fee_accrued
+            .saturating_add(TokenAmount(protocol_fee));
+        self.cumulative_fee_token = self
+            .cumulative_fee_token
+            .saturating_add(TokenAmount(fee_tokens - protocol_fee));
+        self.record_swap_totals(net_tokens_received + fee_tokens, fee_tokens);
+        self.swap_history.push(SwapRecord {
+            timestamp: Self::now(),
+            price: (net_tokens_received as u128 * self.precision_factor as u128
+                / staked_raw as u128) as u64,
+            volume_usd: net_tokens_received + fee_tokens,
+        });
+        // The liquidity provider part of the fee stays in the pool, so the swap never lowers
+        // the value locked
+        self.debug_assert_tvl(old_tvl, 0);
+        self.debug_assert_invariants();
+
+        if let Some(threshold) = self.rebalance_threshold {
+            if (self.reserve_ratio() - 0.5).abs() > threshold {
+                self.rebalance()?;
+            }
+        }
+        self.record_reserve_snapshot(Self::now());
+
+        Ok(net_tokens_received)
+    }
+
+    /// Adds a swap's raw value in tokens and raw fee to the pool totals.
+    fn record_swap_totals(&mut self, volume: u64, fee_tokens: u64) {
+        self.total_volume_swapped = self.total_volume_swapped.saturating_add(volume);
+        self.total_fees_collected = self.total_fees_collected.saturating_add(fee_tokens);
+    }
+
+    /// Calculates the raw part of a swap fee accrued to the treasury.
+    fn protocol_fee_part(&self, fee_tokens: u64) -> u64 {
+        (fee_tokens as u128 * self.protocol_fee_share.0.min(self.precision_factor) as u128
+            / self.precision_factor as u128) as u64
+    }
+
+    /// Calculates the raw amount of tokens a swap of `staked_raw` staked tokens pays out
+    /// and the raw fee it charges, without changing the pool.
+    fn swap_output(&self, staked_raw: u64) -> Result<(u64, u64), LpPoolError> {
+        if staked_raw == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+
+        // Staked tokens are worth `price` tokens each rather than following a constant-product
+        // curve and only the fee reacts to a depleted token reserve, so 6 staked tokens at 1.5
+        // are worth 9 tokens and pay out 8.991 after the fee as in the story example
+        let tokens_received =
+            (staked_raw as u128 * self.price.0 as u128) / self.precision_factor as u128;
+
+        if tokens_received > self.token_amount.0 as u128 {
+            return Err(LpPoolError::InsufficientLiquidity);
+        }
+        let tokens_received = tokens_received as u64;
+
+        let amount_after = self.token_amount.0 - tokens_received;
+
+        let fee = self.fee_for_amount_after(amount_after);
+
+        let precision_adjusted_fee = self.precision_factor.saturating_sub(fee);
+
+        let net_tokens_received = ((tokens_received as u128 * precision_adjusted_fee as u128)
+            / self.precision_factor as u128) as u64;
+
+        Ok((net_tokens_received, tokens_received - net_tokens_received))
+    }
+
+    /// Calculates the raw amount of tokens a trader has to pay the pool for `staked_raw`
+    /// of its staked tokens, without changing the pool.
+    ///
+    /// The fee follows the same curve as `swap_output`, taken at the token reserve after
+    /// the payment, so the smallest payment whose amount after fee buys `staked_raw` at the
+    /// current price is searched for.
+    fn tokens_in_for_staked_out(&self, staked_raw: u64) -> Result<u64, LpPoolError> {
+        if staked_raw == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        if staked_raw > self.st_token_amount.0 {
+            return Err(LpPoolError::InsufficientStakedTokens);
+        }
+        if self.max_fee.0 >= self.precision_factor {
+            return Err(LpPoolError::InvalidFee);
+        }
+
+        let staked_out = |token_in: u128| {
+            let fee = self.fee_for_amount_after((self.token_amount.0 as u128 + token_in) as u64);
+            let fee_tokens = token_in * fee as u128 / self.precision_factor as u128;
+            (token_in - fee_tokens) * self.precision_factor as u128 / self.price.0 as u128
+        };
+        // Paying the maximum fee on the gross value always buys enough
+        let gross =
+            (staked_raw as u128 * self.price.0 as u128).div_ceil(self.precision_factor as u128);
+        let mut low = gross;
+        let mut high = (gross * self.precision_factor as u128)
+            .div_ceil((self.precision_factor - self.max_fee.0) as u128)
+            + 1;
+        if self.token_amount.0 as u128 + high > u64::MAX as u128 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if staked_out(middle) >= staked_raw as u128 {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+
+        Ok(low as u64)
+    }
+
+    /// Converts an amount to fixed-point, failing if it does not fit in a `u64`.
+    fn checked_raw(&self, amount: f64) -> Result<u64, LpPoolError> {
+        let raw = (amount * self.precision_factor as f64).round();
+        if !raw.is_finite() || raw >= u64::MAX as f64 {
+            return Err(LpPoolError::ArithmeticOverflow);
+        }
+        Ok(raw as u64)
+    }
+
+    /// Calculates the raw amount of staked tokens a swap of `token_raw` tokens pays out
+    /// and the raw fee, in tokens, it leaves in the pool, without changing the pool.
+    ///
+    /// The fee follows the same curve as `swap_output` at the token reserve after the swap.
+    fn reverse_swap_output(&self, token_raw: u64) -> Result<(u64, u64), LpPoolError> {
+        if token_raw == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        let amount_after = self
+            .token_amount
+            .0
+            .checked_add(token_raw)
+            .ok_or(LpPoolError::ArithmeticOverflow)?;
+        let fee = self.fee_for_amount_after(amount_after);
+        let fee_tokens = (token_raw as u128 * fee.min(self.precision_factor) as u128
+            / self.precision_factor as u128) as u64;
+
+        // Staked tokens worth of the tokens after fee at the current price
+        let st_received =
+            (token_raw - fee_tokens) as u128 * self.precision_factor as u128 / self.price.0 as u128;
+        if st_received > self.st_token_amount.0 as u128 {
+            return Err(LpPoolError::InsufficientStakedTokens);
+        }
+
+        Ok((st_received as u64, fee_tokens))
+    }
+
+    /// Calculates the raw value of the pool expressed in tokens:
+    /// the token reserve plus the staked reserve at the current price.
+    ///
+    /// LP tokens are minted in proportion to this value, so a deposit of `token_amount`
+    /// receives `token_amount / pool_value * lp_token_supply`.
+    pub fn pool_value(&self) -> u128 {
+        self.token_amount.0 as u128
+            + self.st_token_amount.0 as u128 * self.price.0 as u128 / self.precision_factor as u128
+    }
+
+    /// Calculates the raw fee for a swap leaving `amount_after` tokens in the pool.
+    ///
+    /// The fee follows `fee_model` from `max_fee` at an empty reserve, e.g.
+    /// fee = max_fee - (max_fee - min_fee) * amount_after / liquidity_target for
+    /// `FeeModel::Linear`, with `min_fee` applied once the pool stays at or above the
+    /// liquidity target.
+    fn fee_for_amount_after(&self, amount_after: u64) -> u64 {
+        if amount_after >= self.liquidity_target.0 {
+            return self.min_fee.0;
+        }
+        match self.fee_model {
+            FeeModel::Linear => {
+                self.max_fee.0
+                    - ((self.max_fee.0 - self.min_fee.0) as u128 * amount_after as u128
+                        / self.liquidity_target.0 as u128) as u64
+            }
+            FeeModel::Exponential { exponent } => {
+                let k = exponent as f64 / DEFAULT_PRECISION_FACTOR as f64;
+                let depletion = 1.0 - amount_after as f64 / self.liquidity_target.0 as f64;
+                let weight = (k * depletion).exp_m1() / k.exp_m1();
+                let fee = self.min_fee.0 as f64 + (self.max_fee.0 - self.min_fee.0) as f64 * weight;
+                (fee.round() as u64).clamp(self.min_fee.0, self.max_fee.0)
+            }
+        }
+    }
+
+    /// Inverts the fee curve: calculates the token reserve, in tokens, at which a swap
+    /// pays `fee` (a fraction between `min_fee` and `max_fee`).
+    fn amount_after_for_fee(&self, fee: f64) -> f64 {
+        let min_fee = self.min_fee.0 as f64 / self.precision_factor as f64;
+        let max_fee = self.max_fee.0 as f64 / self.precision_factor as f64;
+        if max_fee == min_fee {
+            return self.token_amount.0 as f64 / self.precision_factor as f64;
+        }
+        let liquidity_target = self.liquidity_target.0 as f64 / self.precision_factor as f64;
+
+        match self.fee_model {
+            FeeModel::Linear => (max_fee - fee) * liquidity_target / (max_fee - min_fee),
+            FeeModel::Exponential { exponent } => {
+                let k = exponent as f64 / DEFAULT_PRECISION_FACTOR as f64;
+                let weight = (fee - min_fee) / (max_fee - min_fee);
+                let depletion = (weight * k.exp_m1()).ln_1p() / k;
+                (1.0 - depletion) * liquidity_target
+            }
+        }
+    }
+
+    /// Calculates how many tokens have to be added to mint exactly the desired amount of LP tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `desired_lp_tokens` - The amount of LP tokens the provider wants to receive.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of tokens to pass to `add_liquidity` or an error.
+    pub fn max_add_liquidity_for_lp_target(
+        &self,
+        desired_lp_tokens: f64,
+    ) -> Result<f64, LpPoolError> {
+        if desired_lp_tokens <= 0.0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        let desired_lp_u64 = (desired_lp_tokens * self.precision_factor as f64).round() as u64;
+
+        let pool_value = self.pool_value();
+        let tokens_needed = if self.lp_token_amount.0 > 0 && pool_value > 0 {
+            // Round up, as minting rounds down
+            (desired_lp_u64 as u128 * pool_value).div_ceil(self.lp_token_amount.0 as u128) as u64
+        } else {
+            // Initial liquidity is minted 1:1 and locks MINIMUM_LIQUIDITY
+            desired_lp_u64 + MINIMUM_LIQUIDITY
+        };
+
+        Ok(tokens_needed as f64 / self.precision_factor as f64)
+    }
+
+    /// Calculates the constant-product invariant `k = token_amount * st_token_amount`.
+    ///
+    /// # Returns
+    ///
+    /// The raw invariant, computed in u128 to prevent overflow.
+    pub fn invariant_k(&self) -> u128 {
+        self.token_amount.0 as u128 * self.st_token_amount.0 as u128
+    }
+
+    /// Panics in debug builds if the invariant decreased, which would indicate value extraction.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_k` - The invariant recorded before the operation.
+    pub fn assert_k_nondecreasing(&self, old_k: u128) {
+        let new_k = self.invariant_k();
+        debug_assert!(
+            new_k >= old_k,
+            "invariant k decreased from {} to {}",
+            old_k,
+            new_k
+        );
+    }
+
+    /// Calculates the execution price of an infinitesimal swap, i.e. the spot price after fees.
+    ///
+    /// An external price equal to this value leaves no arbitrage against the pool.
+    ///
+    /// # Returns
+    ///
+    /// The amount of tokens received per staked token for a marginal swap.
+    pub fn arbitrage_free_price(&self) -> f64 {
+        let fee = self.fee_for_amount_after(self.token_amount.0);
+        let price = self.price.0 as f64 / self.precision_factor as f64;
+
+        price * (1.0 - fee as f64 / self.precision_factor as f64)
+    }
+
+    /// Calculates the share of the pool value held as regular tokens.
+    ///
+    /// # Returns
+    ///
+    /// A ratio in `[0, 1]` where `0.5` is a balanced pool, or `0.0` for an empty pool.
+    pub fn reserve_ratio(&self) -> f64 {
+        let pool_value = self.pool_value();
+        if pool_value == 0 {
+            return 0.0;
+        }
+        self.token_amount.0 as f64 / pool_value as f64
+    }
+
+    /// Re-splits the reserves at the current price so that half of the pool value
+    /// is held as tokens and half as staked tokens.
+    ///
+    /// This is bookkeeping only: the pool has no counterparty to trade with, so nothing
+    /// is bought or sold and no fee is charged. The pool value, and with it the claim of
+    /// every LP token, is conserved up to rounding down. It only runs from `swap` once
+    /// `set_rebalance_threshold` is configured.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success or `LpPoolError::PoolPaused`.
+    fn rebalance(&mut self) -> Result<(), LpPoolError> {
+        self.ensure_not_paused()?;
+        let old_tvl = self.compute_tvl_raw();
+        let pool_value = self.pool_value();
+        let token_amount = pool_value / 2;
+        let st_token_amount =
+            (pool_value - token_amount) * self.precision_factor as u128 / self.price.0 as u128;
+
+        self.token_amount.0 = token_amount as u64;
+        self.st_token_amount.0 = st_token_amount as u64;
+        // Rounding the staked reserve down may lose up to one raw staked token worth of value
+        self.debug_assert_tvl(
+            old_tvl,
+            self.price.0.div_ceil(self.precision_factor) as u128,
+        );
+        self.debug_assert_invariants();
+        Ok(())
+    }
+
+    /// Enables automatic rebalancing at the end of every swap once the reserve ratio
+    /// deviates from `0.5` by more than `threshold`.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - The allowed deviation, must be in `(0, 0.5)`.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success or an error.
+    pub fn set_rebalance_threshold(&mut self, threshold: f64) -> Result<(), LpPoolError> {
+        self.ensure_not_paused()?;
+        if !(threshold > 0.0 && threshold < 0.5) {
+            return Err(LpPoolError::InvalidThreshold);
+        }
+        self.rebalance_threshold = Some(threshold);
+        Ok(())
+    }
+
+    /// Updates the liquidity target, moving the point where the fee curve reaches `min_fee`.
+    /// Reserves and LP tokens are left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_target` - The new target amount of liquidity, must be positive.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success, `LpPoolError::InvalidTokenAmount` or
+    /// `LpPoolError::PoolPaused`.
+    pub fn update_liquidity_target(&mut self, new_target: f64) -> Result<(), LpPoolError> {
+        self.ensure_not_paused()?;
+        if new_target.is_nan() || new_target <= 0.0 || new_target.is_infinite() {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        let new_target = (new_target * self.precision_factor as f64).round() as u64;
+        if new_target == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        self.liquidity_target = TokenAmount(new_target);
+        Ok(())
+    }
+
+    /// Estimates how many days organic trading needs to bring the reserve ratio to `target_ratio`.
+    ///
+    /// The model assumes `daily_volume_fraction` of the pool value is traded every day and that
+    /// the fee curve pushes the ratio back up with a pressure of
+    /// `(current_fee - min_fee) / (max_fee - min_fee)`, i.e. a depleted pool attracts tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_ratio` - The reserve ratio to converge to.
+    /// * `daily_volume_fraction` - The fraction of the pool value traded daily.
+    ///
+    /// # Returns
+    ///
+    /// The estimated days, or `f64::INFINITY` if the pressure moves away from the target.
+    pub fn estimate_convergence_time(&self, target_ratio: f64, daily_volume_fraction: f64) -> f64 {
+        let distance = target_ratio - self.reserve_ratio();
+        if distance.abs() < f64::EPSILON {
+            return 0.0;
+        }
+        if distance < 0.0 || daily_volume_fraction <= 0.0 || self.max_fee.0 == self.min_fee.0 {
+            return f64::INFINITY;
+        }
+
+        let fee = self.fee_for_amount_after(self.token_amount.0);
+        let pressure = (fee - self.min_fee.0) as f64 / (self.max_fee.0 - self.min_fee.0) as f64;
+        if pressure == 0.0 {
+            return f64::INFINITY;
+        }
+
+        distance / (daily_volume_fraction * pressure)
+    }
+
+    /// Calculates the pool reserves after swapping the given amount of staked tokens,
+    /// without changing the pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_in` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing a tuple with the new token and staked token reserves or an error.
+    pub fn token_split_after_swap(&self, staked_in: f64) -> Result<(f64, f64), LpPoolError> {
+        let mut pool = self.clone();
+        pool.swap_unchecked(staked_in)?;
+
+        Ok((
+            pool.token_amount.0 as f64 / self.precision_factor as f64,
+            pool.st_token_amount.0 as f64 / self.precision_factor as f64,
+        ))
+    }
+
+    /// Returns the spot price, i.e. the amount of tokens one staked token is worth before fees.
+    pub fn spot_price_staked_to_token(&self) -> f64 {
+        self.price.0 as f64 / self.precision_factor as f64
+    }
+
+    /// Estimates the amount of tokens a swap would pay out, without changing the pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_in` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of tokens that would be received or an error.
+    pub fn estimate_swap_output(&self, staked_in: f64) -> Result<f64, LpPoolError> {
+        self.validate_swap_inputs(staked_in)?;
+        let staked_raw = (staked_in * self.precision_factor as f64).round() as u64;
+
+        let (tokens_received, _) = self.swap_output(staked_raw)?;
+
+        Ok(tokens_received as f64 / self.precision_factor as f64)
+    }
+
+    /// Calculates the deviation of the execution price from the mid price caused by fees and depth.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_in` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the slippage in basis points or an error.
+    pub fn effective_slippage(&self, staked_in: f64) -> Result<f64, LpPoolError> {
+        let mid_price = self.spot_price_staked_to_token();
+        let execution_price = self.estimate_swap_output(staked_in)? / staked_in;
+
+        Ok((mid_price - execution_price) / mid_price * 10_000.0)
+    }
+
+    /// Checks that a staked token amount can be swapped: it must be positive, finite
+    /// and representable in fixed-point.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_amount` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating valid input or an error.
+    pub fn validate_swap_inputs(&self, staked_amount: f64) -> Result<(), LpPoolError> {
+        if staked_amount.is_nan()
+            || !staked_amount.is_finite()
+            || staked_amount <= 0.0
+            || staked_amount * self.precision_factor as f64 > u64::MAX as f64
+        {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        Ok(())
+    }
+
+    /// Calculates the fee range generating `target_apr_pct` APR for the liquidity providers
+    /// at the expected daily volume.
+    ///
+    /// avg_fee = target_apr * tvl / (365 * daily_volume), min_fee = avg_fee * 0.5, max_fee = avg_fee * 1.5
+    ///
+    /// # Arguments
+    ///
+    /// * `expected_daily_volume` - The expected daily swap volume in tokens.
+    /// * `target_apr_pct` - The target APR percentage.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the `(min_fee, max_fee)` percentages, as taken by `init`, or an error.
+    pub fn compute_optimal_fee_for_volume(
+        &self,
+        expected_daily_volume: f64,
+        target_apr_pct: f64,
+    ) -> Result<(f64, f64), LpPoolError> {
+        if expected_daily_volume <= 0.0 || target_apr_pct < 0.0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        let tvl = self.pool_value() as f64 / self.precision_factor as f64;
+        let avg_fee = target_apr_pct * tvl / (365.0 * expected_daily_volume);
+
+        let min_fee = avg_fee * 0.5;
+        let max_fee = avg_fee * 1.5;
+        if max_fee > 100.0 {
+            return Err(LpPoolError::InvalidFee);
+        }
+
+        Ok((min_fee, max_fee))
+    }
+
+    /// Returns the share of the liquidity target covered by the token reserve, clamped to `[0, 1]`.
+    pub fn utilization_ratio(&self) -> f64 {
+        (self.token_amount.0 as f64 / self.liquidity_target.0 as f64).clamp(0.0, 1.0)
+    }
+
+    /// Calculates a composite liquidity score used by aggregators to rank pools.
+    ///
+    /// The score is the harmonic mean of `utilization_ratio` and the fee tightness
+    /// `1 - (max_fee - min_fee) / max_fee`. A score near 1 means deep liquidity with
+    /// tight fees, near 0 means shallow or expensive.
+    ///
+    /// # Returns
+    ///
+    /// The score in `[0, 1]`.
+    pub fn liquidity_score(&self) -> f64 {
+        let utilization = self.utilization_ratio();
+        let fee_tightness = if self.max_fee.0 == 0 {
+            1.0
+        } else {
+            1.0 - (self.max_fee.0 - self.min_fee.0) as f64 / self.max_fee.0 as f64
+        };
+
+        if utilization + fee_tightness == 0.0 {
+            return 0.0;
+        }
+        (2.0 * utilization * fee_tightness / (utilization + fee_tightness)).clamp(0.0, 1.0)
+    }
+
+    /// Simulates the effect of withdrawing `lp_amount` LP tokens on the pool, without changing it.
+    /// Reserves are assumed to be redeemed proportionally to the LP token share.
+    ///
+    /// # Arguments
+    ///
+    /// * `lp_amount` - The amount of LP tokens being withdrawn.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the `ExitImpact` or an error.
+    pub fn simulate_exit_impact(&self, lp_amount: f64) -> Result<ExitImpact, LpPoolError> {
+        if lp_amount <= 0.0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        let lp_amount_u64 = (lp_amount * self.precision_factor as f64).round() as u64;
+        if lp_amount_u64 == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        if lp_amount_u64 > self.redeemable_lp_supply() {
+            return Err(LpPoolError::InsufficientLiquidity);
+        }
+
+        let remaining_lp = (self.lp_token_amount.0 - lp_amount_u64) as u128;
+        let mut pool = self.clone();
+        pool.token_amount.0 =
+            (self.token_amount.0 as u128 * remaining_lp / self.lp_token_amount.0 as u128) as u64;
+        pool.st_token_amount.0 =
+            (self.st_token_amount.0 as u128 * remaining_lp / self.lp_token_amount.0 as u128) as u64;
+        pool.lp_token_amount.0 = remaining_lp as u64;
+
+        let new_fee = pool.fee_for_amount_after(pool.token_amount.0);
+
+        Ok(ExitImpact {
+            new_tvl: pool.pool_value() as f64 / self.precision_factor as f64,
+            new_fee_at_utilization: new_fee as f64 / self.precision_factor as f64 * 100.0,
+            utilization_change: pool.utilization_ratio() - self.utilization_ratio(),
+            lp_fraction_exiting: lp_amount_u64 as f64 / self.lp_token_amount.0 as f64,
+        })
+    }
+
+    /// Calculates the fee rate applied to a swap of the given amount of staked tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_in` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the fee as a fraction of the swapped value or an error.
+    pub fn effective_fee(&self, staked_in: f64) -> Result<f64, LpPoolError> {
+        self.validate_swap_inputs(staked_in)?;
+        let staked_raw = (staked_in * self.precision_factor as f64).round() as u64;
+
+        let tokens_received =
+            ((staked_raw as u128 * self.price.0 as u128) / self.precision_factor as u128) as u64;
+        if tokens_received > self.token_amount.0 {
+            return Err(LpPoolError::InsufficientLiquidity);
+        }
+        let fee = self.fee_for_amount_after(self.token_amount.0 - tokens_received);
+
+        Ok(fee as f64 / self.precision_factor as f64)
+    }
+
+    /// Calculates the total fee paid for a sequence of swaps routed through several pools.
+    ///
+    /// # Arguments
+    ///
+    /// * `pools` - The pools the swaps are routed through.
+    /// * `amounts` - The amount of staked tokens swapped in each pool.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the sum of all fees in tokens or an error.
+    pub fn compute_swap_path_cost(pools: &[&LpPool], amounts: &[f64]) -> Result<f64, LpPoolError> {
+        if pools.len() != amounts.len() {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+
+        pools
+            .iter()
+            .zip(amounts)
+            .map(|(pool, &amount_in)| {
+                Ok(
+                    amount_in
+                        * pool.spot_price_staked_to_token()
+                        * pool.effective_fee(amount_in)?,
+                )
+            })
+            .sum()
+    }
+
+    /// Returns the current unix timestamp in seconds.
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+
+    /// Records the current reserves if `timestamp` lies in a later hour than the last snapshot.
+    /// Pool operations call this with the current time, so at most one snapshot is kept per hour.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - The unix timestamp in seconds of the operation.
+    pub fn record_reserve_snapshot(&mut self, timestamp: u64) {
+        let hour = timestamp / SECONDS_PER_HOUR;
+        if let Some(last) = self.reserve_ring.last() {
+            if last.timestamp / SECONDS_PER_HOUR >= hour {
+                return;
+            }
+        }
+
+        self.reserve_ring.push(ReserveSnapshot {
+            timestamp,
+            token: self.token_amount.0,
+            st_token: self.st_token_amount.0,
+            price: self.price.0,
+        });
+    }
+
+    /// Returns the hourly reserve snapshots of the last 24 hours.
+    ///
+    /// # Returns
+    ///
+    /// The snapshots sorted chronologically, oldest first.
+    pub fn reserve_snapshot_ring(&self) -> Vec<ReserveSnapshot> {
+        let mut snapshots: Vec<ReserveSnapshot> = self.reserve_ring.iter().copied().collect();
+        snapshots.sort_by_key(|snapshot| snapshot.timestamp);
+        snapshots
+    }
+
+    /// Derives the price implied by the reserve ratio, which equals the market-clearing
+    /// price of a balanced pool even without an external price.
+    ///
+    /// # Returns
+    ///
+    /// The amount of tokens per staked token, or `f64::INFINITY` without staked tokens.
+    pub fn derive_synthetic_price(&self) -> f64 {
+        if self.st_token_amount.0 == 0 {
+            return f64::INFINITY;
+        }
+        self.token_amount.0 as f64 / self.st_token_amount.0 as f64
+    }
+
+    /// Calculates the execution price of a swap, i.e. the tokens received per staked token after fees.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_in` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the effective price or an error.
+    pub fn effective_price_after_fees(&self, staked_in: f64) -> Result<f64, LpPoolError> {
+        Ok(self.spot_price_staked_to_token() * (1.0 - self.effective_fee(staked_in)?))
+    }
+
+    /// Calculates the trade bringing the pool's effective price to the external price.
+    ///
+    /// The fee curve is inverted to find the token reserve at which the effective price equals
+    /// `external_price`: staked tokens have to be swapped in while the pool is priced above it,
+    /// tokens have to be added while it is priced below it.
+    ///
+    /// # Arguments
+    ///
+    /// * `external_price` - The external amount of tokens per staked token.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the direction and the amount to trade (staked tokens for
+    /// `StakedToToken`, tokens for `TokenToStaked`) or an error if the price is unreachable.
+    pub fn compute_equilibrium_trade(
+        &self,
+        external_price: f64,
+    ) -> Result<(SwapDirection, f64), LpPoolError> {
+        let spot_price = self.spot_price_staked_to_token();
+        if external_price <= 0.0 || !external_price.is_finite() {
+            return Err(LpPoolError::InvalidPrice);
+        }
+
+        let min_fee = self.min_fee.0 as f64 / self.precision_factor as f64;
+        let max_fee = self.max_fee.0 as f64 / self.precision_factor as f64;
+        let target_fee = 1.0 - external_price / spot_price;
+        if target_fee < min_fee || target_fee > max_fee {
+            return Err(LpPoolError::InvalidPrice);
+        }
+
+        let token_amount = self.token_amount.0 as f64 / self.precision_factor as f64;
+        let amount_after = self.amount_after_for_fee(target_fee);
+
+        if amount_after <= token_amount {
+            Ok((
+                SwapDirection::StakedToToken,
+                (token_amount - amount_after) / spot_price,
+            ))
+        } else {
+            Ok((SwapDirection::TokenToStaked, amount_after - token_amount))
+        }
+    }
+
+    /// Calculates the profit and loss of a liquidity position at the current pool state.
+    ///
+    /// The impermanent loss follows from the price change since the deposit,
+    /// `1 - 2 * sqrt(r) / (1 + r)` of the deposit value with `r = price / deposit_price`.
+    /// The fee earnings are the position's LP share of the swap fees accumulated since
+    /// the deposit.
+    ///
+    /// # Arguments
+    ///
+    /// * `position` - The position to evaluate.
+    /// * `current_time` - The unix timestamp in seconds to evaluate at.
+    ///
+    /// # Returns
+    ///
+    /// The `PositionPnl` of the position.
+    pub fn position_pnl(&self, position: &LiquidityPosition, current_time: u64) -> PositionPnl {
+        let current_value = if self.lp_token_amount.0 == 0 {
+            0.0
+        } else {
+            position.lp_amount * self.pool_value() as f64 / self.lp_token_amount.0 as f64
+        };
+        let unrealized_gain = current_value - position.deposit_value;
+
+        let price_ratio = self.spot_price_staked_to_token() / position.deposit_price;
+        let impermanent_loss =
+            (1.0 - 2.0 * price_ratio.sqrt() / (1.0 + price_ratio)) * position.deposit_value;
+        let fee_earnings = if self.lp_token_amount.0 == 0 {
+            0.0
+        } else {
+            let fees_since_deposit = self.cumulative_fee_token.0 as f64
+                / self.precision_factor as f64
+                - position.deposit_cumulative_fee;
+            fees_since_deposit * position.lp_amount * self.precision_factor as f64
+                / self.lp_token_amount.0 as f64
+        };
+
+        PositionPnl {
+            unrealized_gain,
+            fee_earnings,
+            impermanent_loss,
+            net_pnl: fee_earnings - impermanent_loss,
+            holding_days: current_time.saturating_sub(position.deposit_time) as f64
+                / SECONDS_PER_DAY as f64,
+        }
+    }
+
+    /// Calculates the market depth around the spot price for order book style visualization.
+    ///
+    /// Price levels are spread evenly from `spot - 5%` to `spot + 5%`. The cumulative liquidity
+    /// at a level is the amount of tokens the pool pays out in swaps executing at prices between
+    /// the lowest level and that level.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_points` - The number of price levels.
+    ///
+    /// # Returns
+    ///
+    /// The `(price, cumulative_liquidity)` pairs ordered by price.
+    pub fn market_depth_curve(&self, num_points: usize) -> Vec<(f64, f64)> {
+        let spot_price = self.spot_price_staked_to_token();
+        let low_price = spot_price * 0.95;
+        let step = if num_points > 1 {
+            spot_price * 0.1 / (num_points - 1) as f64
+        } else {
+            0.0
+        };
+
+        let tokens_at_low_price = self.tokens_tradable_at_price(low_price);
+        (0..num_points)
+            .map(|i| {
+                let price = low_price + step * i as f64;
+                (
+                    price,
+                    tokens_at_low_price - self.tokens_tradable_at_price(price),
+                )
+            })
+            .collect()
+    }
+
+    /// Calculates the amount of tokens swaps can take out of the pool while
+    /// executing at an effective price of at least `price_level`.
+    fn tokens_tradable_at_price(&self, price_level: f64) -> f64 {
+        let min_fee = self.min_fee.0 as f64 / self.precision_factor as f64;
+        let max_fee = self.max_fee.0 as f64 / self.precision_factor as f64;
+        let token_amount = self.token_amount.0 as f64 / self.precision_factor as f64;
+
+        let fee = 1.0 - price_level / self.spot_price_staked_to_token();
+        if fee < min_fee {
+            0.0
+        } else if fee > max_fee {
+            token_amount
+        } else {
+            (token_amount - self.amount_after_for_fee(fee)).max(0.0)
+        }
+    }
+
+    /// Calculates the raw total value locked in tokens:
+    /// `token_amount + st_token_amount * price / precision_factor`.
+    pub fn compute_tvl_raw(&self) -> u128 {
+        self.pool_value()
+    }
+
+    /// Panics in debug builds if the pool lost more value than `fee_collected`
+    /// since `old_tvl` was recorded.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_tvl` - The raw total value locked before the operation.
+    /// * `fee_collected` - The raw value legitimately leaving the pool during the operation.
+    fn debug_assert_tvl(&self, old_tvl: u128, fee_collected: u128) {
+        let new_tvl = self.compute_tvl_raw();
+        debug_assert!(
+            new_tvl >= old_tvl.saturating_sub(fee_collected),
+            "pool value dropped from {} to {} with only {} collected",
+            old_tvl,
+            new_tvl,
+            fee_collected
+        );
+    }
+
+    /// Calculates the amount of staked tokens to swap to bring the reserve ratio down to `target_ratio`.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_ratio` - The reserve ratio to reach, must not exceed the current one.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of staked tokens or an error if swaps cannot reach the target.
+    pub fn optimal_swap_amount(&self, target_ratio: f64) -> Result<f64, LpPoolError> {
+        let current_ratio = self.reserve_ratio();
+        if (current_ratio - target_ratio).abs() < f64::EPSILON {
+            return Ok(0.0);
+        }
+        if !(0.0..=1.0).contains(&target_ratio) || target_ratio > current_ratio {
+            return Err(LpPoolError::InvalidThreshold);
+        }
+
+        let ratio_after = |staked_raw: u64| -> Option<f64> {
+            let (tokens_out, _) = self.swap_output(staked_raw).ok()?;
+            let token_amount = (self.token_amount.0 - tokens_out) as u128;
+            let st_value = (self.st_token_amount.0 + staked_raw) as u128 * self.price.0 as u128
+                / self.precision_factor as u128;
+            Some(token_amount as f64 / (token_amount + st_value) as f64)
+        };
+
+        // Bisect over the swap sizes the token reserve can pay out
+        let mut low = 0_u64;
+        let mut high = self.raw_max_swap_in();
+        if ratio_after(high).is_none_or(|ratio| ratio > target_ratio) {
+            return Err(LpPoolError::InsufficientLiquidity);
+        }
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            match ratio_after(mid) {
+                Some(ratio) if ratio > target_ratio => low = mid,
+                _ => high = mid,
+            }
+        }
+
+        Ok(high as f64 / self.precision_factor as f64)
+    }
+
+    /// Calculates the fee paid for the swap bringing the reserve ratio to `target_ratio`.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_ratio` - The reserve ratio to reach.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the fee in tokens, `0.0` if the pool is already at the target, or an error.
+    pub fn compute_rebalancing_cost(&self, target_ratio: f64) -> Result<f64, LpPoolError> {
+        let staked_in = self.optimal_swap_amount(target_ratio)?;
+        if staked_in == 0.0 {
+            return Ok(0.0);
+        }
+
+        Ok(staked_in * self.spot_price_staked_to_token() * self.effective_fee(staked_in)?)
+    }
+
+    /// Checks that the pool holds at least `MINIMUM_LIQUIDITY`. Below it only
+    /// `add_liquidity` is allowed.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating enough liquidity or `LpPoolError::InsufficientLiquidity`.
+    pub fn enforce_min_liquidity_constant(&self) -> Result<(), LpPoolError> {
+        if self.compute_tvl_raw() < MINIMUM_LIQUIDITY as u128 {
+            return Err(LpPoolError::InsufficientLiquidity);
+        }
+        Ok(())
+    }
+
+    /// Swaps staked tokens for regular tokens, paying a referral fee out of the user's output.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_amount` - The amount of staked tokens to swap.
+    /// * `referrer_fee_bps` - The referral fee in basis points, at most 500 (5%).
+    ///
+    /// # Returns
+    ///
+    /// A result containing a tuple with the tokens received by the user and by the referrer or an error.
+    pub fn swap_with_referral(
+        &mut self,
+        staked_amount: f64,
+        referrer_fee_bps: u16,
+    ) -> Result<(f64, f64), LpPoolError> {
+        if referrer_fee_bps > MAX_REFERRER_FEE_BPS {
+            return Err(LpPoolError::InvalidFee);
+        }
+        self.validate_swap_inputs(staked_amount)?;
+        let staked_raw = (staked_amount * self.precision_factor as f64).round() as u64;
+
+        let tokens_received = self.swap_raw(staked_raw)?;
+        let referrer_fee = (tokens_received as u128 * referrer_fee_bps as u128 / 10_000) as u64;
+        let user_received = tokens_received - referrer_fee;
+
+        Ok((
+            user_received as f64 / self.precision_factor as f64,
+            referrer_fee as f64 / self.precision_factor as f64,
+        ))
+    }
+
+    /// Checks that the price implied by the reserves stays within `max_deviation_bps` of the
+    /// configured price, as a larger deviation may indicate manipulation. A pool without
+    /// staked tokens implies no price and passes the check.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_deviation_bps` - The allowed deviation in basis points.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success or `LpPoolError::PriceDeviation`.
+    pub fn internal_price_check(&self, max_deviation_bps: u64) -> Result<(), LpPoolError> {
+        if self.st_token_amount.0 == 0 {
+            return Ok(());
+        }
+        let configured = self.spot_price_staked_to_token();
+        let observed = self.derive_synthetic_price();
+
+        let deviation_bps = (observed - configured).abs() / configured * 10_000.0;
+        if deviation_bps > max_deviation_bps as f64 {
+            return Err(LpPoolError::PriceDeviation {
+                configured,
+                observed,
+            });
+        }
+        Ok(())
+    }
+
+    /// Enables the price deviation check at the start of every swap.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_deviation_bps` - The allowed deviation in basis points.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success or `LpPoolError::PoolPaused`.
+    pub fn set_max_price_deviation(&mut self, max_deviation_bps: u64) -> Result<(), LpPoolError> {
+        self.ensure_not_paused()?;
+        self.max_price_deviation_bps = Some(max_deviation_bps);
+        Ok(())
+    }
+
+    /// Calculates the share of the accumulated swap fees claimable with `lp_amount` LP tokens,
+    /// without burning them.
+    ///
+    /// # Arguments
+    ///
+    /// * `lp_amount` - The amount of LP tokens held.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the claimable fee in tokens or an error.
+    pub fn compute_lp_fee_share(&self, lp_amount: f64) -> Result<f64, LpPoolError> {
+        if lp_amount <= 0.0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        let lp_amount_u64 = (lp_amount * self.precision_factor as f64).round() as u64;
+        if lp_amount_u64 == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+        if lp_amount_u64 > self.lp_token_amount.0 {
+            return Err(LpPoolError::InsufficientLiquidity);
+        }
+
+        let fee_share = self.cumulative_fee_token.0 as u128 * lp_amount_u64 as u128
+            / self.lp_token_amount.0 as u128;
+
+        Ok(fee_share as f64 / self.precision_factor as f64)
+    }
+
+    /// Halts the pool: every method changing the reserves, LP tokens, price, fees or pool
+    /// configuration fails with `LpPoolError::PoolPaused` until `unpause` is called. Read-only
+    /// queries and `record_reserve_snapshot` keep working.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resumes a pool halted by `pause`.
+    pub fn unpause(&mut self) {
+        self.paused = false;
+    }
+
+    /// Checks that the pool is not paused.
+    fn ensure_not_paused(&self) -> Result<(), LpPoolError> {
+        if self.paused {
+            return Err(LpPoolError::PoolPaused);
+        }
+        Ok(())
+    }
+
+    /// Checks that the anti-snipe delay configured at launch has passed.
+    ///
+    /// # Arguments
+    ///
+    /// * `current_timestamp` - The unix timestamp in seconds to check at.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating an active pool or `LpPoolError::PoolNotYetActive`.
+    pub fn anti_snipe_delay(&self, current_timestamp: u64) -> Result<(), LpPoolError> {
+        if current_timestamp.saturating_sub(self.launch_timestamp)
+            < self.anti_snipe_blocks * BLOCK_TIME_SECONDS
+        {
+            return Err(LpPoolError::PoolNotYetActive);
+        }
+        Ok(())
+    }
+
+    /// Calculates the volume-weighted average execution price of the swaps in the time window.
+    ///
+    /// vwap = sum(price_i * volume_i) / sum(volume_i)
+    ///
+    /// # Arguments
+    ///
+    /// * `window_seconds` - The length of the window ending at `current_time`.
+    /// * `current_time` - The unix timestamp in seconds the window ends at.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the VWAP in tokens per staked token or
+    /// `LpPoolError::InsufficientData` if no volume was swapped in the window.
+    pub fn compute_vwap(&self, window_seconds: u64, current_time: u64) -> Result<f64, LpPoolError> {
+        let window_start = current_time.saturating_sub(window_seconds);
+        let (weighted_price, volume) = self
+            .swap_history
+            .iter()
+            .filter(|record| record.timestamp >= window_start && record.timestamp <= current_time)
+            .fold((0_u128, 0_u128), |(weighted_price, volume), record| {
+                (
+                    weighted_price + record.price as u128 * record.volume_usd as u128,
+                    volume + record.volume_usd as u128,
+                )
+            });
+
+        if volume == 0 {
+            return Err(LpPoolError::InsufficientData);
+        }
+        Ok(weighted_price as f64 / volume as f64 / self.precision_factor as f64)
+    }
+
+    /// Finds the worst-case liquidity provider loss for prices within `price_range`.
+    ///
+    /// The impermanent loss `1 - 2 * sqrt(r) / (1 + r)`, with `r` the price relative to the
+    /// current one, has no closed-form maximum over the fee curve of this pool, so the range
+    /// is sampled at `LOSS_SCENARIO_SAMPLES` evenly spaced prices.
+    ///
+    /// # Arguments
+    ///
+    /// * `price_range` - The lowest and highest price to consider.
+    ///
+    /// # Returns
+    ///
+    /// The `MaxLossScenario` within the range.
+    pub fn maximum_loss_scenario(&self, price_range: (f64, f64)) -> MaxLossScenario {
+        let low = price_range.0.min(price_range.1).max(f64::MIN_POSITIVE);
+        let high = price_range.0.max(price_range.1).max(low);
+        let current_price = self.spot_price_staked_to_token();
+        let token_amount = self.token_amount.0 as f64 / self.precision_factor as f64;
+        let st_token_amount = self.st_token_amount.0 as f64 / self.precision_factor as f64;
+        let pool_value_at = |price: f64| token_amount + st_token_amount * price;
+
+        let step = (high - low) / (LOSS_SCENARIO_SAMPLES - 1) as f64;
+        let mut scenario = MaxLossScenario {
+            worst_price: low,
+            max_il: 0.0,
+            max_il_price: current_price.clamp(low, high),
+            pool_value_at_worst: pool_value_at(low),
+        };
+        for i in 0..LOSS_SCENARIO_SAMPLES {
+            let price = low + step * i as f64;
+            let price_ratio = price / current_price;
+            let il = 1.0 - 2.0 * price_ratio.sqrt() / (1.0 + price_ratio);
+            if il > scenario.max_il {
+                scenario.max_il = il;
+                scenario.max_il_price = price;
+            }
+            if pool_value_at(price) < scenario.pool_value_at_worst {
+                scenario.worst_price = price;
+                scenario.pool_value_at_worst = pool_value_at(price);
+            }
+        }
+
+        scenario
+    }
+
+    /// Encodes a pool operation as instruction data: a one byte tag (`0x01` add liquidity,
+    /// `0x02` swap, `0x03` remove liquidity) followed by the amount scaled by
+    /// `DEFAULT_PRECISION_FACTOR` as a little-endian u64, whatever the pool's precision.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The operation to encode.
+    ///
+    /// # Returns
+    ///
+    /// The 9 bytes of instruction data.
+    pub fn encode_instruction(op: PoolOp) -> Vec<u8> {
+        let (tag, amount) = match op {
+            PoolOp::AddLiquidity(amount) => (0x01, amount),
+            PoolOp::Swap(amount) => (0x02, amount),
+            PoolOp::RemoveLiquidity(amount) => (0x03, amount),
+        };
+        let amount_u64 = (amount * DEFAULT_PRECISION_FACTOR as f64).round() as u64;
+
+        let mut data = Vec::with_capacity(9);
+        data.push(tag);
+        data.extend_from_slice(&amount_u64.to_le_bytes());
+        data
+    }
+
+    /// Decodes instruction data produced by `encode_instruction`.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The instruction data.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the decoded `PoolOp` or `LpPoolError::InvalidInstruction`.
+    ///
+    /// The decoded amount is in tokens rather than raw units, so it does not depend on the
+    /// pool the operation is applied to. A pool with a coarser precision factor rounds it
+    /// like any other amount passed to its methods.
+    pub fn decode_instruction(bytes: &[u8]) -> Result<PoolOp, LpPoolError> {
+        let (tag, amount_bytes) = bytes.split_first().ok_or(LpPoolError::InvalidInstruction)?;
+        let amount_bytes: [u8; 8] = amount_bytes
+            .try_into()
+            .map_err(|_| LpPoolError::InvalidInstruction)?;
+        let amount = u64::from_le_bytes(amount_bytes) as f64 / DEFAULT_PRECISION_FACTOR as f64;
+
+        match tag {
+            0x01 => Ok(PoolOp::AddLiquidity(amount)),
+            0x02 => Ok(PoolOp::Swap(amount)),
+            0x03 => Ok(PoolOp::RemoveLiquidity(amount)),
+            _ => Err(LpPoolError::InvalidInstruction),
+        }
+    }
+
+    /// Returns the total amount of LP tokens issued.
+    pub fn lp_token_supply(&self) -> f64 {
+        self.lp_token_amount.0 as f64 / self.precision_factor as f64
+    }
+
+    /// Returns the swap fee revenue accumulated by the pool.
+    ///
+    /// # Returns
+    ///
+    /// A tuple with the fees in tokens and the fees as a fraction of the pool value.
+    pub fn fee_revenue(&self) -> (f64, f64) {
+        let fee_tokens = self.cumulative_fee_token.0 as f64 / self.precision_factor as f64;
+        let pool_value = self.pool_value();
+        if pool_value == 0 {
+            return (fee_tokens, 0.0);
+        }
+        (
+            fee_tokens,
+            self.cumulative_fee_token.0 as f64 / pool_value as f64,
+        )
+    }
+
+    /// Distributes the fee revenue over LP token holders proportionally to their holdings.
+    ///
+    /// # Arguments
+    ///
+    /// * `holders` - The `(holder_id, lp_amount)` pairs, covering the whole LP token supply.
+    ///
+    /// # Returns
+    ///
+    /// A result containing each holder's share of the fees in tokens, in input order, or an error
+    /// if the holdings do not add up to the LP token supply.
+    pub fn expected_fee_revenue_distribution(
+        &self,
+        holders: &[(u64, f64)],
+    ) -> Result<Vec<f64>, LpPoolError> {
+        let total_lp: f64 = holders.iter().map(|(_, lp_amount)| lp_amount).sum();
+        let total_lp_u64 = (total_lp * self.precision_factor as f64).round() as u64;
+        if total_lp_u64 != self.lp_token_amount.0 || total_lp_u64 == 0 {
+            return Err(LpPoolError::InvalidTokenAmount);
+        }
+
+        let (fee_tokens, _) = self.fee_revenue();
+        Ok(holders
+            .iter()
+            .map(|(_, lp_amount)| fee_tokens * lp_amount / total_lp)
+            .collect())
+    }
+
+    /// Updates the pool price to an external market price. The reserves are left untouched,
+    /// only future swaps use the new price.
+    ///
+    /// The implied arbitrage opportunity is what the stale price was worth to an arbitrageur:
+    /// after a drop, selling staked tokens to the pool for its whole token reserve; after a
+    /// rise, buying its whole staked reserve for tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_price` - The new price in tokens per staked token.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the `PriceImpact` of the update, `LpPoolError::InvalidPrice` or
+    /// `LpPoolError::PoolPaused`.
+    pub fn update_price(&mut self, new_price: f64) -> Result<PriceImpact, LpPoolError> {
+        self.ensure_not_paused()?;
+        if new_price.is_nan() || new_price <= 0.0 || new_price.is_infinite() {
+            return Err(LpPoolError::InvalidPrice);
+        }
+        let new_price_raw = (new_price * self.precision_factor as f64).round() as u64;
+        if new_price_raw == 0 {
+            return Err(LpPoolError::InvalidPrice);
+        }
+
+        let old_price = self.spot_price_staked_to_token();
+        self.price = Price(new_price_raw);
+        let new_price = self.spot_price_staked_to_token();
+
+        let implied_arb_opportunity = if new_price < old_price {
+            let token_reserve = self.token_amount.0 as f64 / self.precision_factor as f64;
+            token_reserve * (old_price - new_price) / old_price
+        } else {
+            let staked_reserve = self.st_token_amount.0 as f64 / self.precision_factor as f64;
+            staked_reserve * (new_price - old_price)
+        };
+
+        Ok(PriceImpact {
+            old_price,
+            new_price,
+            implied_arb_opportunity,
+        })
+    }
+
+    /// Updates the pool price from an oracle, e.g. Pyth or Chainlink, unless its confidence
+    /// interval is too wide relative to the price.
+    ///
+    /// # Arguments
+    ///
+    /// * `oracle_price` - The price reported by the oracle.
+    /// * `confidence` - The confidence interval (standard deviation) of the price.
+    /// * `max_confidence_threshold` - The largest accepted `confidence / oracle_price`.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success or an error, leaving the price unchanged.
+    pub fn apply_price_oracle_update(
+        &mut self,
+        oracle_price: f64,
+        confidence: f64,
+        max_confidence_threshold: f64,
+    ) -> Result<(), LpPoolError> {
+        self.ensure_not_paused()?;
+        if oracle_price <= 0.0 || !oracle_price.is_finite() {
+            return Err(LpPoolError::InvalidPrice);
+        }
+        if confidence.is_nan() || confidence / oracle_price >= max_confidence_threshold {
+            return Err(LpPoolError::LowOracleConfidence {
+                confidence,
+                price: oracle_price,
+            });
+        }
+
+        self.update_price(oracle_price)?;
+        Ok(())
+    }
+
+    /// Records the current pool value and LP token supply, e.g. when a position is opened.
+    pub fn pool_snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            timestamp: Self::now(),
+            lp_supply: self.lp_token_supply(),
+            tvl: self.pool_value() as f64 / self.precision_factor as f64,
+        }
+    }
+
+    /// Calculates the current value in tokens of `lp_amount` LP tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `lp_amount` - The amount of LP tokens.
+    ///
+    /// # Returns
+    ///
+    /// The share of the pool value, `0.0` for a pool without LP tokens.
+    pub fn current_exit_value(&self, lp_amount: f64) -> f64 {
+        if self.lp_token_amount.0 == 0 {
+            return 0.0;
+        }
+        lp_amount * self.pool_value() as f64 / self.lp_token_amount.0 as f64
+    }
+
+    /// Calculates the profit or loss of holding `lp_held` LP tokens since `entry_snapshot`.
+    ///
+    /// pnl = current_exit_value(lp_held) - lp_held / entry_lp_supply * entry_tvl
+    ///
+    /// # Arguments
+    ///
+    /// * `entry_snapshot` - The pool snapshot taken when the LP tokens were acquired.
+    /// * `lp_held` - The amount of LP tokens held.
+    ///
+    /// # Returns
+    ///
+    /// The profit in tokens, negative for a loss.
+    pub fn compute_realized_pnl_for_lp(&self, entry_snapshot: &PoolSnapshot, lp_held: f64) -> f64 {
+        let entry_value = if entry_snapshot.lp_supply == 0.0 {
+            0.0
+        } else {
+            lp_held / entry_snapshot.lp_supply * entry_snapshot.tvl
+        };
+        self.current_exit_value(lp_held) - entry_value
+    }
+
+    /// Calculates the marginal execution price left after swapping the given amount of staked tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_in` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the `arbitrage_free_price` of the pool after the swap or an error.
+    pub fn price_after_swap(&self, staked_in: f64) -> Result<f64, LpPoolError> {
+        self.validate_swap_inputs(staked_in)?;
+        let staked_raw = (staked_in * self.precision_factor as f64).round() as u64;
+
+        self.raw_price_after_swap(staked_raw)
+    }
+
+    /// Calculates the marginal execution price left after swapping `staked_raw` staked tokens.
+    fn raw_price_after_swap(&self, staked_raw: u64) -> Result<f64, LpPoolError> {
+        let (tokens_out, _) = self.swap_output(staked_raw)?;
+        let fee = self.fee_for_amount_after(self.token_amount.0 - tokens_out);
+
+        Ok(self.spot_price_staked_to_token() * (1.0 - fee as f64 / self.precision_factor as f64))
+    }
+
+    /// Calculates the smallest amount of staked tokens whose swap brings the marginal execution
+    /// price down to `target_price`, using bisection.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_price` - The marginal execution price to reach.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of staked tokens or `LpPoolError::InvalidPrice` if swaps
+    /// cannot reach the target price.
+    pub fn compute_min_staked_for_target_price(
+        &self,
+        target_price: f64,
+    ) -> Result<f64, LpPoolError> {
+        let current_price = self.arbitrage_free_price();
+        if !target_price.is_finite() || target_price > current_price {
+            return Err(LpPoolError::InvalidPrice);
+        }
+        if target_price == current_price {
+            return Ok(0.0);
+        }
+
+        let mut low = 0_u64;
+        let mut high = self.raw_max_swap_in();
+        if self
+            .raw_price_after_swap(high)
+            .map_or(true, |price| price > target_price)
+        {
+            return Err(LpPoolError::InvalidPrice);
+        }
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            match self.raw_price_after_swap(mid) {
+                Ok(price) if price > target_price => low = mid,
+                _ => high = mid,
+            }
+        }
+
+        Ok(high as f64 / self.precision_factor as f64)
+    }
+
+    /// Adds liquidity at most once per `nonce`, so retried transactions do not mint twice.
+    ///
+    /// # Arguments
+    ///
+    /// * `amount` - The amount of tokens to add to the pool.
+    /// * `nonce` - The unique identifier of the request.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of LP tokens received, the recorded result for an already
+    /// processed nonce, or an error.
+    pub fn add_liquidity_idempotent(
+        &mut self,
+        amount: f64,
+        nonce: u64,
+    ) -> Result<f64, LpPoolError> {
+        if let Some(lp_tokens) = self.processed_nonces.get(&nonce) {
+            return Ok(*lp_tokens);
+        }
+
+        let lp_tokens = self.add_liquidity(amount)?;
+        self.processed_nonces.insert(nonce, lp_tokens);
+        Ok(lp_tokens)
+    }
+
+    /// Returns the largest amount of staked tokens the token reserve can pay for at the pool price.
+    pub fn max_swap_in(&self) -> f64 {
+        self.raw_max_swap_in() as f64 / self.precision_factor as f64
+    }
+
+    /// Returns the largest raw amount of staked tokens the token reserve can pay for.
+    fn raw_max_swap_in(&self) -> u64 {
+        (self.token_amount.0 as u128 * self.precision_factor as u128 / self.price.0 as u128) as u64
+    }
+
+    /// Calculates the price impact of a swap: the percentage by which its execution price
+    /// falls short of the spot price.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_in` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the price impact percentage or an error.
+    pub fn price_impact(&self, staked_in: f64) -> Result<f64, LpPoolError> {
+        Ok(self.effective_slippage(staked_in)? / 100.0)
+    }
+
+    /// Calculates the largest amount of staked tokens whose swap has a price impact of at most
+    /// `max_impact_pct`, using binary search over `[0, max_swap_in]`.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_impact_pct` - The price impact budget in percent.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of staked tokens, `0.0` if no swap fits the budget, or an error.
+    pub fn compute_maximum_swap_for_bounded_impact(
+        &self,
+        max_impact_pct: f64,
+    ) -> Result<f64, LpPoolError> {
+        if max_impact_pct.is_nan() || max_impact_pct < 0.0 {
+            return Err(LpPoolError::InvalidThreshold);
+        }
+        let within_budget = |staked_raw: u64| {
+            self.price_impact(staked_raw as f64 / self.precision_factor as f64)
+                .is_ok_and(|impact| impact <= max_impact_pct)
+        };
+
+        let mut low = 0_u64;
+        let mut high = self.raw_max_swap_in();
+        if within_budget(high) {
+            return Ok(high as f64 / self.precision_factor as f64);
+        }
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if within_budget(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low as f64 / self.precision_factor as f64)
+    }
+
+    /// Resets the pool to the state right after `init`: reserves, LP tokens, accumulated and
+    /// protocol fees, swap totals and histories are cleared and the launch time restarts now.
+    ///
+    /// The configuration passed to `init` (price, liquidity target, fees, fee model, protocol
+    /// fee share, precision factor and anti-snipe blocks), the rebalance threshold and the
+    /// maximum price deviation are kept.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success or `LpPoolError::PoolPaused`.
+    pub fn reset_to_initial(&mut self) -> Result<(), LpPoolError> {
+        self.ensure_not_paused()?;
+        self.token_amount = TokenAmount(0);
+        self.st_token_amount = StakedTokenAmount(0);
+        self.lp_token_amount = LpTokenAmount(0);
+        self.locked_lp_tokens = LpTokenAmount(0);
+        self.protocol_fee_accrued = TokenAmount(0);
+        self.cumulative_fee_token = TokenAmount(0);
+        self.launch_timestamp = Self::now();
+        self.total_volume_swapped = 0;
+        self.total_fees_collected = 0;
+        self.reserve_ring = RingBuffer::new();
+        self.swap_history = RingBuffer::new();
+        self.processed_nonces.clear();
+        Ok(())
+    }
+
+    /// Calculates the accumulated swap fees per LP token, isolating the fee component of the
+    /// LP token value.
+    ///
+    /// # Returns
+    ///
+    /// The fees in tokens per LP token, `0.0` for a pool without LP tokens.
+    pub fn fee_income_per_lp_token(&self) -> f64 {
+        if self.lp_token_amount.0 == 0 {
+            return 0.0;
+        }
+        self.cumulative_fee_token.0 as f64 / self.lp_token_amount.0 as f64
+    }
+
+    /// Calculates the net asset value of one LP token in tokens.
+    ///
+    /// # Returns
+    ///
+    /// The pool value per LP token, `1.0` for a pool without LP tokens as the first
+    /// deposit mints 1:1.
+    pub fn lp_token_nav_per_token(&self) -> f64 {
+        if self.lp_token_amount.0 == 0 {
+            return 1.0;
+        }
+        self.pool_value() as f64 / self.lp_token_amount.0 as f64
+    }
+
+    /// Calculates the current redemption value of LP tokens received from `add_liquidity`.
+    ///
+    /// # Arguments
+    ///
+    /// * `lp_received` - The amount of LP tokens.
+    ///
+    /// # Returns
+    ///
+    /// The value in tokens.
+    pub fn compute_add_liquidity_worth(&self, lp_received: f64) -> f64 {
+        lp_received * self.lp_token_nav_per_token()
+    }
+
+    /// Calculates how hard it is to manipulate the pool price, relative to the pool size.
+    ///
+    /// The cost is the value of the swap needed to push the marginal execution price 10% down.
+    /// The score is `cost / (tvl + cost)`, so it approaches 1 as manipulation gets expensive
+    /// relative to the pool value, and is 1 when the fee curve cannot move the price that far.
+    ///
+    /// # Returns
+    ///
+    /// The score in `[0, 1]`.
+    pub fn price_manipulation_resistance_score(&self) -> f64 {
+        let tvl = self.pool_value() as f64 / self.precision_factor as f64;
+        if tvl == 0.0 {
+            return 0.0;
+        }
+
+        let target_price = self.arbitrage_free_price() * 0.9;
+        let cost_to_move_price_10_pct = match self.compute_min_staked_for_target_price(target_price)
+        {
+            Ok(staked_in) => staked_in * self.spot_price_staked_to_token(),
+            Err(_) => return 1.0,
+        };
+
+        (cost_to_move_price_10_pct / (tvl + cost_to_move_price_10_pct)).clamp(0.0, 1.0)
+    }
+
+    /// Packs the pool into a fixed-size account data blob.
+    ///
+    /// Layout, all integers little-endian, unused bytes zeroed:
+    ///
+    /// | Offset | Size | Field                                                  |
+    /// |--------|------|--------------------------------------------------------|
+    /// | 0      | 1    | discriminator `0x4c`                                   |
+    /// | 1      | 1    | layout version `1`                                     |
+    /// | 8      | 8    | `price`                                                |
+    /// | 16     | 8    | `token_amount`                                         |
+    /// | 24     | 8    | `st_token_amount`                                      |
+    /// | 32     | 8    | `lp_token_amount`                                      |
+    /// | 40     | 8    | `liquidity_target`                                     |
+    /// | 48     | 8    | `min_fee`                                              |
+    /// | 56     | 8    | `max_fee`                                              |
+    /// | 64     | 8    | `cumulative_fee_token`                                 |
+    /// | 72     | 8    | `launch_timestamp`                                     |
+    /// | 80     | 8    | `anti_snipe_blocks`                                    |
+    /// | 88     | 8    | `rebalance_threshold` as f64 bits, `0` for none        |
+    /// | 96     | 8    | `max_price_deviation_bps`, `u64::MAX` for none         |
+    /// | 104    | 8    | `fee_model` exponent, `0` for `FeeModel::Linear`       |
+    /// | 112    | 8    | `protocol_fee_share`                                   |
+    /// | 120    | 8    | `protocol_fee_accrued`                                 |
+    /// | 128    | 8    | `locked_lp_tokens`                                     |
+    /// | 136    | 8    | `paused`, `1` if paused                                |
+    /// | 144    | 8    | `total_volume_swapped`                                 |
+    /// | 152    | 8    | `total_fees_collected`                                 |
+    /// | 160    | 8    | `precision_factor`, `0` for `DEFAULT_PRECISION_FACTOR` |
+    ///
+    /// The reserve snapshots, swap history and processed nonces are not stored.
+    ///
+    /// # Returns
+    ///
+    /// The 256 bytes of account data.
+    pub fn serialize_to_account_data(&self) -> [u8; ACCOUNT_DATA_SIZE] {
+        let fields = [
+            self.price.0,
+            self.token_amount.0,
+            self.st_token_amount.0,
+            self.lp_token_amount.0,
+            self.liquidity_target.0,
+            self.min_fee.0,
+            self.max_fee.0,
+            self.cumulative_fee_token.0,
+            self.launch_timestamp,
+            self.anti_snipe_blocks,
+            self.rebalance_threshold.map_or(0, f64::to_bits),
+            self.max_price_deviation_bps.unwrap_or(u64::MAX),
+            match self.fee_model {
+                FeeModel::Linear => 0,
+                FeeModel::Exponential { exponent } => exponent,
+            },
+            self.protocol_fee_share.0,
+            self.protocol_fee_accrued.0,
+            self.locked_lp_tokens.0,
+            self.paused as u64,
+            self.total_volume_swapped,
+            self.total_fees_collected,
+            self.precision_factor,
+        ];
+
+        let mut data = [0_u8; ACCOUNT_DATA_SIZE];
+        data[0] = ACCOUNT_DISCRIMINATOR;
+        data[1] = ACCOUNT_VERSION;
+        for (i, field) in fields.iter().enumerate() {
+            let offset = 8 + i * 8;
+            data[offset..offset + 8].copy_from_slice(&field.to_le_bytes());
+        }
+        data
+    }
+
+    /// Restores a pool from account data produced by `serialize_to_account_data`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The 256 bytes of account data.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the restored `LpPool` or `LpPoolError::InvalidConfig` if the data
+    /// has another discriminator or version or holds out-of-range values.
+    pub fn from_account_data(data: &[u8; ACCOUNT_DATA_SIZE]) -> Result<LpPool, LpPoolError> {
+        if data[0] != ACCOUNT_DISCRIMINATOR || data[1] != ACCOUNT_VERSION {
+            return Err(LpPoolError::InvalidConfig);
+        }
+        let field = |i: usize| {
+            let offset = 8 + i * 8;
+            let mut bytes = [0_u8; 8];
+            bytes.copy_from_slice(&data[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        let precision_factor = match field(19) {
+            0 => DEFAULT_PRECISION_FACTOR,
+            precision_factor => precision_factor,
+        };
+        if !Self::is_valid_precision_factor(precision_factor) {
+            return Err(LpPoolError::InvalidConfig);
+        }
+        let price = field(0);
+        let liquidity_target = field(4);
+        let min_fee = field(5);
+        let max_fee = field(6);
+        if price == 0
+            || liquidity_target == 0
+            || min_fee > max_fee
+            || max_fee > precision_factor
+            || field(13) > precision_factor
+            || field(15) > field(3)
+            || field(16) > 1
+        {
+            return Err(LpPoolError::InvalidConfig);
+        }
+        let rebalance_threshold = match field(10) {
+            0 => None,
+            bits => Some(f64::from_bits(bits)),
+        };
+        if let Some(threshold) = rebalance_threshold {
+            if !(threshold > 0.0 && threshold < 0.5) {
+                return Err(LpPoolError::InvalidConfig);
+            }
+        }
+
+        let fee_model = match field(12) {
+            0 => FeeModel::Linear,
+            exponent => FeeModel::Exponential { exponent },
+        };
+        let mut pool = LpPool::init(1.0, 1.0, 0.0, 0.0, fee_model, 0.0, precision_factor)
+            .map_err(|_| LpPoolError::InvalidConfig)?;
+        pool.price = Price(price);
+        pool.token_amount = TokenAmount(field(1));
+        pool.st_token_amount = StakedTokenAmount(field(2));
+        pool.lp_token_amount = LpTokenAmount(field(3));
+        pool.locked_lp_tokens = LpTokenAmount(field(15));
+        pool.liquidity_target = TokenAmount(liquidity_target);
+        pool.min_fee = Percentage(min_fee);
+        pool.max_fee = Percentage(max_fee);
+        pool.cumulative_fee_token = TokenAmount(field(7));
+        pool.total_volume_swapped = field(17);
+        pool.total_fees_collected = field(18);
+        pool.protocol_fee_share = Percentage(field(13));
+        pool.protocol_fee_accrued = TokenAmount(field(14));
+        pool.launch_timestamp = field(8);
+        pool.anti_snipe_blocks = field(9);
+        pool.paused = field(16) == 1;
+        pool.rebalance_threshold = rebalance_threshold;
+        pool.max_price_deviation_bps = match field(11) {
+            u64::MAX => None,
+            max_deviation_bps => Some(max_deviation_bps),
+        };
+
+        Ok(pool)
+    }
+
+    /// Clones the pool with both fees set to zero, isolating the pool mechanics from fee effects.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the fee-less copy of the pool.
+    pub fn with_zero_fee(&self) -> Result<LpPool, LpPoolError> {
+        let mut pool = self.clone();
+        // the copy is only used for analysis, so a paused pool can be copied too
+        pool.min_fee = Percentage(0);
+        pool.max_fee = Percentage(0);
+        Ok(pool)
+    }
+
+    /// Sets both fees of the pool to zero.
+    ///
+    /// # Returns
+    ///
+    /// A result indicating success or `LpPoolError::PoolPaused`.
+    pub fn with_fees_disabled(&mut self) -> Result<(), LpPoolError> {
+        self.ensure_not_paused()?;
+        self.min_fee = Percentage(0);
+        self.max_fee = Percentage(0);
+        Ok(())
+    }
+
+    /// Calculates the short positions hedging an LP position against price moves.
+    ///
+    /// The position holds `lp_value / tvl` of each reserve, so shorting that fraction of both
+    /// reserves offsets it. This assumes the pool composition stays constant; swaps and
+    /// rebalancing change it and require adjusting the hedge.
+    ///
+    /// # Arguments
+    ///
+    /// * `lp_value` - The value of the LP position in tokens.
+    ///
+    /// # Returns
+    ///
+    /// A tuple with the token and staked token amounts to short.
+    pub fn compute_delta_neutral_hedge(&self, lp_value: f64) -> (f64, f64) {
+        let pool_value = self.pool_value();
+        if pool_value == 0 {
+            return (0.0, 0.0);
+        }
+        let lp_fraction = lp_value * self.precision_factor as f64 / pool_value as f64;
+
+        (
+            lp_fraction * self.token_amount.0 as f64 / self.precision_factor as f64,
+            lp_fraction * self.st_token_amount.0 as f64 / self.precision_factor as f64,
+        )
+    }
+
+    /// Calculates the divergence loss of a liquidity position between two prices.
+    ///
+    /// Uses the closed form `1 - 2 * sqrt(r) / (1 + r)` with `r = final_price / initial_price`.
+    ///
+    /// # Arguments
+    ///
+    /// * `initial_price` - The price when the position was opened.
+    /// * `final_price` - The price to evaluate the position at.
+    ///
+    /// # Returns
+    ///
+    /// The loss as a fraction of the value of holding the deposited reserves.
+    pub fn expected_divergence_loss(&self, initial_price: f64, final_price: f64) -> f64 {
+        let price_ratio = final_price / initial_price;
+        1.0 - 2.0 * price_ratio.sqrt() / (1.0 + price_ratio)
+    }
+
+    /// Calculates the impermanent loss of a liquidity position between two prices.
+    ///
+    /// Compares the value of a constant product position rebalanced to `final_price` with
+    /// the value of holding its reserves from `initial_price`.
+    ///
+    /// # Arguments
+    ///
+    /// * `initial_price` - The price when the position was opened.
+    /// * `final_price` - The price to evaluate the position at.
+    ///
+    /// # Returns
+    ///
+    /// The loss as a fraction of the value of holding the deposited reserves.
+    pub fn impermanent_loss(&self, initial_price: f64, final_price: f64) -> f64 {
+        // Reserves of a position with invariant k = 1 at a price
+        let reserves_at = |price: f64| (price.sqrt(), 1.0 / price.sqrt());
+
+        let (token_held, st_token_held) = reserves_at(initial_price);
+        let held_value = token_held + st_token_held * final_price;
+        let (token_pooled, st_token_pooled) = reserves_at(final_price);
+        let pooled_value = token_pooled + st_token_pooled * final_price;
+
+        1.0 - pooled_value / held_value
+    }
+
+    /// Checks the pool state and collects every invariant violation.
+    ///
+    /// Unlike the constructors, which stop at the first invalid value, this reports all
+    /// problems at once, which helps when inspecting states restored from external sources.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the state is consistent, otherwise a human-readable description of each
+    /// violation.
+    pub fn validate_state_consistency(&self) -> Result<(), Vec<String>> {
+        self.check_invariants().map_err(|violations| {
+            violations
+                .iter()
+                .flat_map(|violation| self.describe_violation(violation))
+                .collect()
+        })
+    }
+
+    /// Describes an `InvariantViolation` found in this pool, one line per broken condition.
+    fn describe_violation(&self, violation: &InvariantViolation) -> Vec<String> {
+        let percent = |fee: u64| fee as f64 * 100.0 / self.precision_factor as f64;
+        match *violation {
+            InvariantViolation::ZeroPrice => vec!["price must be positive".to_string()],
+            InvariantViolation::OrphanedReserves { .. } => {
+                vec!["reserves are not backed by any LP tokens".to_string()]
+            }
+            InvariantViolation::LpSupplyExceedsTvl { lp_supply, tvl } => vec![format!(
+                "{} LP tokens are backed by only {} tokens of reserves",
+                lp_supply as f64 / self.precision_factor as f64,
+                tvl as f64 / self.precision_factor as f64
+            )],
+            InvariantViolation::FeeOutOfRange { min_fee, max_fee } => {
+                let mut descriptions = Vec::new();
+                if max_fee > self.precision_factor {
+                    descriptions.push(format!("max fee {}% exceeds 100%", percent(max_fee)));
+                }
+                if min_fee > max_fee {
+                    descriptions.push(format!(
+                        "min fee {}% exceeds max fee {}%",
+                        percent(min_fee),
+                        percent(max_fee)
+                    ));
+                }
+                descriptions
+            }
+            InvariantViolation::ZeroLiquidityTarget => {
+                vec!["liquidity target must be positive".to_string()]
+            }
+            InvariantViolation::RebalanceThresholdOutOfRange { threshold } => {
+                vec![format!(
+                    "rebalance threshold {threshold} is outside (0, 0.5)"
+                )]
+            }
+        }
+    }
+
+    /// Swaps staked tokens if the price impact stays within `max_impact_bps`, otherwise
+    /// deposits them with `add_liquidity_single_sided`.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_amount` - The amount of staked tokens to swap or deposit.
+    /// * `max_impact_bps` - The largest acceptable price impact in basis points.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the tokens received and `true` if the swap executed, or the LP
+    /// tokens received and `false` if the staked tokens were deposited, or an error.
+    pub fn try_swap_or_add_liquidity(
+        &mut self,
+        staked_amount: f64,
+        max_impact_bps: u64,
+    ) -> Result<(f64, bool), LpPoolError> {
+        self.validate_swap_inputs(staked_amount)?;
+        match self.effective_slippage(staked_amount) {
+            Ok(impact_bps) if impact_bps <= max_impact_bps as f64 => {
+                Ok((self.swap_unchecked(staked_amount)?, true))
+            }
+            Ok(_) | Err(LpPoolError::InsufficientLiquidity) => {
+                Ok((self.add_liquidity_single_sided(staked_amount)?, false))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the value of both reserves in tokens at the pool price.
+    pub fn net_asset_value(&self) -> f64 {
+        self.pool_value() as f64 / self.precision_factor as f64
+    }
+
+    /// Returns the amount of LP tokens one token is worth, `1.0` for a pool without LP tokens
+    /// as the first deposit mints 1:1.
+    pub fn token_to_lp_conversion_rate(&self) -> f64 {
+        if self.lp_token_amount.0 == 0 || self.pool_value() == 0 {
+            return 1.0;
+        }
+        self.lp_token_supply() / self.net_asset_value()
+    }
+
+    /// Returns the amount of tokens one LP token is worth, `1.0` for a pool without LP tokens
+    /// as the first deposit mints 1:1.
+    pub fn lp_to_token_conversion_rate(&self) -> f64 {
+        if self.lp_token_amount.0 == 0 || self.pool_value() == 0 {
+            return 1.0;
+        }
+        self.net_asset_value() / self.lp_token_supply()
+    }
+
+    /// Previews the unstake fee `remove_liquidity(lp_amount)` would charge now.
+    ///
+    /// # Arguments
+    ///
+    /// * `lp_amount` - The amount of LP tokens to remove.
+    ///
+    /// # Returns
+    ///
+    /// The fee valued in tokens at the pool price, `0.0` if the removal is not possible.
+    pub fn expected_fee_on_remove(&self, lp_amount: f64) -> f64 {
+        let lp_raw = (lp_amount * self.precision_factor as f64).round() as u64;
+        self.removal_output(lp_raw)
+            .map_or(0.0, |(_, _, unstake_fee)| {
+                unstake_fee as f64 * self.price.0 as f64
+                    / self.precision_factor as f64
+                    / self.precision_factor as f64
+            })
+    }
+
+    /// Estimates a swap of staked tokens for tokens exactly as `swap` would execute it,
+    /// without changing the pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `staked_token_amount` - The amount of staked tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the `SwapEstimate` or an error.
+    pub fn estimate_swap(&self, staked_token_amount: f64) -> Result<SwapEstimate, LpPoolError> {
+        self.validate_swap_inputs(staked_token_amount)?;
+        let staked_raw = (staked_token_amount * self.precision_factor as f64).round() as u64;
+        let (net_tokens, fee_tokens) = self.swap_output(staked_raw)?;
+
+        let tokens_out = net_tokens as f64 / self.precision_factor as f64;
+        let spot_value = staked_token_amount * self.spot_price_staked_to_token();
+        Ok(SwapEstimate {
+            fee_rate: self.fee_for_amount_after(self.token_amount.0 - net_tokens - fee_tokens)
+                as f64
+                / self.precision_factor as f64,
+            tokens_out,
+            price_impact_bps: ((1.0 - tokens_out / spot_value) * 10_000.0)
+                .round()
+                .max(0.0) as u64,
+            effective_price: tokens_out / staked_token_amount,
+        })
+    }
+
+    /// Estimates a swap of tokens for staked tokens exactly as `swap_tokens_for_staked` would
+    /// execute it, without changing the pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_amount` - The amount of tokens to swap.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the `SwapEstimate` or an error.
+    pub fn estimate_swap_tokens_for_staked(
+        &self,
+        token_amount: f64,
+    ) -> Result<SwapEstimate, LpPoolError> {
+        self.validate_swap_inputs(token_amount)?;
+        let token_raw = (token_amount * self.precision_factor as f64).round() as u64;
+        let (st_received, fee_tokens) = self.reverse_swap_output(token_raw)?;
+
+        let st_out = st_received as f64 / self.precision_factor as f64;
+        let spot_value = token_amount / self.spot_price_staked_to_token();
+        Ok(SwapEstimate {
+            fee_rate: fee_tokens as f64 / token_raw as f64,
+            tokens_out: st_out,
+            price_impact_bps: ((1.0 - st_out / spot_value) * 10_000.0).round().max(0.0) as u64,
+            effective_price: token_amount / st_out,
+        })
+    }
+
+    /// Collects aggregate metrics of the pool without changing it.
+    pub fn get_stats(&self) -> PoolStats {
+        PoolStats {
+            tvl_tokens: self.net_asset_value(),
+            depletion_ratio: 1.0 - self.utilization_ratio(),
+            current_fee_rate: self.fee_for_amount_after(self.token_amount.0) as f64
+                / self.precision_factor as f64,
+            lp_token_price: self.lp_token_nav_per_token(),
+            cumulative_volume: self.total_volume_swapped as f64 / self.precision_factor as f64,
+            cumulative_fees: self.total_fees_collected as f64 / self.precision_factor as f64,
+        }
+    }
+
+    /// Checks the pool state for inconsistencies. Pool operations assert this in debug builds,
+    /// see `debug_assert_invariants`.
+    ///
+    /// `validate_state_consistency` reports the same violations as readable descriptions.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the state is consistent, otherwise every `InvariantViolation` found.
+    pub fn check_invariants(&self) -> Result<(), Vec<InvariantViolation>> {
+        let mut violations = Vec::new();
+        let tvl = self.pool_value();
+
+        if self.price.0 == 0 {
+            violations.push(InvariantViolation::ZeroPrice);
+        }
+        if self.lp_token_amount.0 == 0 && tvl > 0 {
+            violations.push(InvariantViolation::OrphanedReserves { tvl });
+        }
+        if self.lp_token_amount.0 as u128 > tvl {
+            violations.push(InvariantViolation::LpSupplyExceedsTvl {
+                lp_supply: self.lp_token_amount.0,
+                tvl,
+            });
+        }
+        if self.min_fee.0 > self.max_fee.0 || self.max_fee.0 > self.precision_factor {
+            violations.push(InvariantViolation::FeeOutOfRange {
+                min_fee: self.min_fee.0,
+                max_fee: self.max_fee.0,
+            });
+        }
+        if self.liquidity_target.0 == 0 {
+            violations.push(InvariantViolation::ZeroLiquidityTarget);
+        }
+        if let Some(threshold) = self.rebalance_threshold {
+            if !(threshold > 0.0 && threshold < 0.5) {
+                violations.push(InvariantViolation::RebalanceThresholdOutOfRange { threshold });
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Panics in debug builds if `check_invariants` finds a corrupted state. A pool worth less
+    /// than its LP supply is a loss after a price drop rather than corruption, so
+    /// `InvariantViolation::LpSupplyExceedsTvl` is tolerated.
+    fn debug_assert_invariants(&self) {
+        if let Err(violations) = self.check_invariants() {
+            debug_assert!(
+                violations.iter().all(|violation| matches!(
+                    violation,
+                    InvariantViolation::LpSupplyExceedsTvl { .. }
+                )),
+                "pool invariants violated: {violations:?}"
+            );
+        }
+    }
+
+    /// Pays out the protocol fees accrued since the last collection.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the amount of tokens collected or an error.
+    pub fn collect_protocol_fees(&mut self) -> Result<f64, LpPoolError> {
+        self.ensure_not_paused()?;
+        let collected = std::mem::replace(&mut self.protocol_fee_accrued, TokenAmount(0));
+        Ok(collected.0 as f64 / self.precision_factor as f64)
+    }
+}
+
+impl fmt::Display for LpPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let to_units = |raw: u64| raw as f64 / self.precision_factor as f64;
+
+        writeln!(f, "LpPool State")?;
+        writeln!(f, "  {:<17}{:>10.6}", "Price:", to_units(self.price.0))?;
+        writeln!(
+            f,
+            "  {:<17}{:>10.6}",
+            "Token Reserve:",
+            to_units(self.token_amount.0)
+        )?;
+        writeln!(
+            f,
+            "  {:<17}{:>10.6}",
+            "Staked Reserve:",
+            to_units(self.st_token_amount.0)
+        )?;
+        writeln!(
+            f,
+            "  {:<17}{:>10.6}",
+            "LP Supply:",
+            to_units(self.lp_token_amount.0)
+        )?;
+        writeln!(
+            f,
+            "  {:<17}{:>10.6}",
+            "Liquidity Target:",
+            to_units(self.liquidity_target.0)
+        )?;
+        write!(
+            f,
+            "  {:<17}{:>6.2}% \u{2013} {:.2}%",
+            "Fee Range:",
+            to_units(self.min_fee.0) * 100.0,
+            to_units(self.max_fee.0) * 100.0
+        )
+    }
+}
+
+/// Represents the TOML layout of a pool configuration.
+#[cfg(feature = "toml")]
+#[derive(Serialize, Deserialize)]
+struct TomlConfig {
+    pool: TomlPool,
+}
+
+/// Represents the `[pool]` section in human units.
+#[cfg(feature = "toml")]
+#[derive(Serialize, Deserialize)]
+struct TomlPool {
+    price: f64,
+    liquidity_target: f64,
+    /// The automatic rebalancing threshold, absent if disabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rebalance_threshold: Option<f64>,
+    /// The allowed price deviation in basis points, absent if the check is disabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_price_deviation_bps: Option<u64>,
+    /// The number of blocks swaps are disabled for after the pool is created.
+    #[serde(default)]
+    anti_snipe_blocks: u64,
+    /// The raw units per token, `DEFAULT_PRECISION_FACTOR` if absent.
+    #[serde(default = "default_precision_factor")]
+    precision_factor: u64,
+    fees: TomlFees,
+}
+
+#[cfg(feature = "toml")]
+fn default_precision_factor() -> u64 {
+    DEFAULT_PRECISION_FACTOR
+}
+
+/// Represents the `[pool.fees]` section as percentages.
+#[cfg(feature = "toml")]
+#[derive(Serialize, Deserialize)]
+struct TomlFees {
+    min_fee: f64,
+    max_fee: f64,
+    /// The curvature of `FeeModel::Exponential`, absent for `FeeModel::Linear`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exponent: Option<f64>,
+    /// The percentage of each swap fee accrued to the treasury.
+    #[serde(default)]
+    protocol_fee_share: f64,
+}
+
+#[cfg(feature = "toml")]
+impl LpPool {
+    /// Creates an empty pool from a TOML configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The TOML document, as produced by `to_toml`.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the configured `LpPool` or an error if the document is malformed
+    /// or holds out-of-range values.
+    pub fn from_toml(s: &str) -> Result<LpPool, LpPoolError> {
+        let config: TomlConfig = toml::from_str(s).map_err(|_| LpPoolError::InvalidConfig)?;
+        let pool = config.pool;
+
+        if !pool.price.is_finite() || pool.price <= 0.0 {
+            return Err(LpPoolError::InvalidConfig);
+        }
+        if let Some(threshold) = pool.rebalance_threshold {
+            if !(threshold > 0.0 && threshold < 0.5) {
+                return Err(LpPoolError::InvalidConfig);
+            }
+        }
+
+        let mut lp_pool = LpPool::init_with_anti_snipe(
+            pool.price,
+            pool.liquidity_target,
+            pool.fees.min_fee,
+            pool.fees.max_fee,
+            pool.fees
+                .exponent
+                .map_or(FeeModel::Linear, FeeModel::exponential),
+            pool.fees.protocol_fee_share,
+            pool.precision_factor,
+            pool.anti_snipe_blocks,
+        )?;
+        lp_pool.rebalance_threshold = pool.rebalance_threshold;
+        lp_pool.max_price_deviation_bps = pool.max_price_deviation_bps;
+
+        Ok(lp_pool)
+    }
+
+    /// Serializes the pool configuration to TOML in human units.
+    ///
+    /// Reserves and LP tokens are state rather than configuration and are not included,
+    /// so `from_toml` creates an empty pool with the same settings.
+    ///
+    /// # Returns
+    ///
+    /// The TOML document with a `[pool]` section and a `[pool.fees]` subsection.
+    pub fn to_toml(&self) -> String {
+        let config = TomlConfig {
+            pool: TomlPool {
+                price: self.price.0 as f64 / self.precision_factor as f64,
+                liquidity_target: self.liquidity_target.0 as f64 / self.precision_factor as f64,
+                rebalance_threshold: self.rebalance_threshold,
+                max_price_deviation_bps: self.max_price_deviation_bps,
+                anti_snipe_blocks: self.anti_snipe_blocks,
+                precision_factor: self.precision_factor,
+                fees: TomlFees {
+                    min_fee: self.min_fee.0 as f64 / self.precision_factor as f64 * 100.0,
+                    max_fee: self.max_fee.0 as f64 / self.precision_factor as f64 * 100.0,
+                    exponent: match self.fee_model {
+                        FeeModel::Linear => None,
+                        FeeModel::Exponential { exponent } => {
+                            Some(exponent as f64 / DEFAULT_PRECISION_FACTOR as f64)
+                        }
+                    },
+                    protocol_fee_share: self.protocol_fee_share.0 as f64
+                        / self.precision_factor as f64
+                        * 100.0,
+                },
+            },
+        };
+
+        toml::to_string(&config).expect("pool configuration is always representable in TOML")
+    }
+}
+
+/// Calculates the profit of arbitraging `amount` staked tokens between two pools.
+///
+/// The staked tokens are bought in the pool with the lower effective price, paying the
+/// fee its curve charges on the tokens paid in, and sold in the pool with the higher
+/// effective price.
+///
+/// # Arguments
+///
+/// * `pool_a` - The first pool.
+/// * `pool_b` - The second pool.
+/// * `amount` - The amount of staked tokens to arbitrage.
+///
+/// # Returns
+///
+/// The net profit in tokens, negative if the price differential does not cover the fees
+/// and `f64::NEG_INFINITY` if either pool cannot execute its leg.
+pub fn cross_pool_arbitrage_profit(pool_a: &LpPool, pool_b: &LpPool, amount: f64) -> f64 {
+    let (output_a, output_b) = match (
+        pool_a.estimate_swap_output(amount),
+        pool_b.estimate_swap_output(amount),
+    ) {
+        (Ok(output_a), Ok(output_b)) => (output_a, output_b),
+        _ => return f64::NEG_INFINITY,
+    };
+    let (cheap_pool, expensive_output) = if output_a <= output_b {
+        (pool_a, output_b)
+    } else {
+        (pool_b, output_a)
+    };
+
+    let staked_raw = (amount * cheap_pool.precision_factor as f64).round() as u64;
+    let input = match cheap_pool.tokens_in_for_staked_out(staked_raw) {
+        Ok(input) => input as f64 / cheap_pool.precision_factor as f64,
+        Err(_) => return f64::NEG_INFINITY,
+    };
+
+    expensive_output - input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_pool() -> LpPool {
+        LpPool::init(
+            1.5,  // price
+            90.0, // liquidity_target
+            0.1,  // min_fee
+            9.0,  // max_fee
+            FeeModel::Linear,
+            0.0, // protocol_fee_share
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_init() {
+        let pool = setup_pool();
+
+        assert_eq!(pool.price.0, (1.5 * DEFAULT_PRECISION_FACTOR as f64).round() as u64);
+        assert_eq!(pool.token_amount.0, 0);
+        assert_eq!(pool.st_token_amount.0, 0);
+        assert_eq!(pool.lp_token_amount.0, 0);
+        assert_eq!(pool.liquidity_target.0, (90.0 * DEFAULT_PRECISION_FACTOR as f64).round() as u64);
+        assert_eq!(pool.min_fee.0, (0.1 * 0.01 * DEFAULT_PRECISION_FACTOR as f64).round() as u64);
+        assert_eq!(pool.max_fee.0, (9.0 * 0.01 * DEFAULT_PRECISION_FACTOR as f64).round() as u64);
+    }
+
+    #[test]
+    fn test_init_rejects_invalid_price() {
+        for price in [0.0, -1.5, f64::NAN, f64::INFINITY, 1e-9] {
+            assert!(matches!(
+                LpPool::init(
+                    price,
+                    90.0,
+                    0.1,
+                    9.0,
+                    FeeModel::Linear,
+                    0.0,
+                    DEFAULT_PRECISION_FACTOR
+                ),
+                Err(LpPoolError::InvalidPrice)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_add_liquidity() {
+        let mut pool = setup_pool();
+
+        let lp_tokens = pool.add_liquidity(100.0).unwrap();
+
+        // MINIMUM_LIQUIDITY of the first deposit is locked
+        assert_eq!(lp_tokens, 99.999);
+        assert_eq!(pool.pool_value(), 100 * DEFAULT_PRECISION_FACTOR as u128);
+
+        // After a swap the pool is worth more than its LP supply
+        pool.swap(6.0, 0.0).unwrap();
+        assert_eq!(pool.pool_value(), 100_009_000);
+        assert_eq!(pool.add_liquidity(10.0).unwrap(), 9.9991);
+    }
+
+    #[test]
+    fn test_add_liquidity_mints_against_pool_value() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        // 100.009 tokens of value back 100 LP tokens, so 10 tokens mint 9.9991 of them
+        let expected_lp = 10 * DEFAULT_PRECISION_FACTOR as u128 * pool.lp_token_amount.0 as u128
+            / pool.pool_value();
+        let lp_tokens = pool.add_liquidity(10.0).unwrap();
+
+        assert_eq!(
+            lp_tokens,
+            expected_lp as f64 / DEFAULT_PRECISION_FACTOR as f64
+        );
+        assert_eq!(lp_tokens, 9.9991);
+    }
+
+    #[test]
+    fn test_add_liquidity_does_not_dilute() {
+        let mut pool = setup_pool();
+        let first_lp = pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        pool.swap(20.0, 0.0).unwrap();
+
+        let share_value =
+            |pool: &LpPool, lp: f64| lp * pool.pool_value() as f64 / pool.lp_token_amount.0 as f64;
+        let first_value = share_value(&pool, first_lp);
+        let second_lp = pool.add_liquidity(100.0).unwrap();
+
+        assert!((share_value(&pool, first_lp) - first_value).abs() < 1e-5);
+        assert!((share_value(&pool, second_lp) - 100.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_swap_successful() {
+        let mut pool = setup_pool();
+        let _ = pool.add_liquidity(100.0);
+
+        let staked_tokens_to_swap = 6.0;
+        let expected_tokens_received = 8.991; // Expected value based on pool's swap logic.
+
+        let result = pool.swap(staked_tokens_to_swap, 0.0).unwrap();
+
+        assert_eq!(result, expected_tokens_received);
+        // assert!((result - expected_tokens_received).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_story_example() {
+        let mut pool = setup_pool();
+        let token_return = pool.add_liquidity(100.0).unwrap();
+        assert_eq!(token_return, 99.999);
+        let swap_return = pool.swap(6.0, 0.0).unwrap();
+        let expected_tokens_received = 8.991;
+        assert!((swap_return - expected_tokens_received).abs() < 0.001);
+        let second_token_return = pool.add_liquidity(10.0).unwrap();
+        assert_eq!(second_token_return, 9.9991);
+        let second_swap_return = pool.swap(30.0, 0.0).unwrap();
+        assert_eq!(second_swap_return, 43.44237);
+        // Everything but the locked 0.001 LP tokens is withdrawn
+        let (remove_token, staked_token) = pool.remove_liquidity(109.9981).unwrap();
+        assert_eq!(remove_token, 57.566106);
+        assert_eq!(staked_token, 35.999672);
+    }
+
+    #[test]
+    fn test_max_add_liquidity_for_lp_target() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        let desired_lp_tokens = 9.9991;
+        let tokens_needed = pool
+            .max_add_liquidity_for_lp_target(desired_lp_tokens)
+            .unwrap();
+        let lp_tokens = pool.add_liquidity(tokens_needed).unwrap();
+
+        assert!((lp_tokens - desired_lp_tokens).abs() < 0.000002);
+    }
+
+    #[test]
+    fn test_max_add_liquidity_for_lp_target_before_swaps() {
+        let pool = setup_pool();
+
+        assert_eq!(
+            pool.max_add_liquidity_for_lp_target(100.0).unwrap(),
+            100.001
+        );
+        assert!(pool.max_add_liquidity_for_lp_target(0.0).is_err());
+    }
+
+    #[test]
+    fn test_invariant_k_increases_after_swaps() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let k_before_first_swap = pool.invariant_k();
+        pool.swap(6.0, 0.0).unwrap();
+        assert!(pool.invariant_k() > k_before_first_swap);
+        pool.assert_k_nondecreasing(k_before_first_swap);
+
+        let k_before_second_swap = pool.invariant_k();
+        pool.swap(30.0, 0.0).unwrap();
+        assert!(pool.invariant_k() > k_before_second_swap);
+        pool.assert_k_nondecreasing(k_before_second_swap);
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_assert_k_nondecreasing_panics() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        let k = pool.invariant_k();
+        pool.token_amount.0 -= 1;
+        pool.assert_k_nondecreasing(k);
+    }
+
+    #[test]
+    fn test_arbitrage_free_price_at_liquidity_target() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(90.0).unwrap();
+
+        let spot_price = pool.price.0 as f64 / DEFAULT_PRECISION_FACTOR as f64;
+        let expected = spot_price * (1.0 - pool.min_fee.0 as f64 / DEFAULT_PRECISION_FACTOR as f64);
+
+        assert!((pool.arbitrage_free_price() - expected).abs() < 1e-12);
+        assert!((pool.arbitrage_free_price() - 1.4985).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_auto_rebalance_threshold() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        assert!(pool.set_rebalance_threshold(0.0).is_err());
+        assert!(pool.set_rebalance_threshold(0.5).is_err());
+        pool.set_rebalance_threshold(0.05).unwrap();
+
+        let mut unbalanced = setup_pool();
+        unbalanced.add_liquidity(100.0).unwrap();
+
+        // Without rebalancing this leaves ~43 tokens against 60 tokens worth of staked tokens
+        let tokens_received = pool.swap(40.0, 0.0).unwrap();
+        assert_eq!(unbalanced.swap(40.0, 0.0).unwrap(), tokens_received);
+        assert!((unbalanced.reserve_ratio() - 0.5).abs() > 0.05);
+
+        assert!((pool.reserve_ratio() - 0.5).abs() < 1e-6);
+        // Re-splitting the reserves neither creates nor destroys value, it only rounds down
+        assert!(pool.pool_value() <= unbalanced.pool_value());
+        assert!(unbalanced.pool_value() - pool.pool_value() <= 2);
+        assert_eq!(pool.lp_token_amount.0, unbalanced.lp_token_amount.0);
+    }
+
+    #[test]
+    fn test_estimate_convergence_time() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(40.0, 0.0).unwrap();
+
+        let days = pool.estimate_convergence_time(0.5, 0.1);
+        assert!(days.is_finite() && days > 0.0);
+        // More volume converges faster
+        assert!(pool.estimate_convergence_time(0.5, 0.2) < days);
+
+        assert_eq!(pool.estimate_convergence_time(0.1, 0.1), f64::INFINITY);
+        assert_eq!(
+            pool.estimate_convergence_time(pool.reserve_ratio(), 0.1),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_token_split_after_swap() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        for staked_in in [1.0, 30.0, 66.0] {
+            let (token_amount, st_token_amount) = pool.token_split_after_swap(staked_in).unwrap();
+
+            let mut swapped = pool.clone();
+            swapped.swap(staked_in, 0.0).unwrap();
+            assert_eq!(
+                token_amount,
+                swapped.token_amount.0 as f64 / DEFAULT_PRECISION_FACTOR as f64
+            );
+            assert_eq!(
+                st_token_amount,
+                swapped.st_token_amount.0 as f64 / DEFAULT_PRECISION_FACTOR as f64
+            );
+        }
+
+        // 67 staked tokens are worth more than the whole token reserve
+        assert!(pool.token_split_after_swap(67.0).is_err());
+        assert_eq!(pool.token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(pool.st_token_amount.0, 0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_gas_optimized_swap_matches_swap() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let mut raw_pool = pool.clone();
+
+        for staked_raw in [6_000_000, 1_234_567, 30_000_001] {
+            let tokens = pool
+                .swap(staked_raw as f64 / DEFAULT_PRECISION_FACTOR as f64, 0.0)
+                .unwrap();
+            let tokens_raw = raw_pool.gas_optimized_swap(staked_raw).unwrap();
+
+            assert!((tokens * DEFAULT_PRECISION_FACTOR as f64 - tokens_raw as f64).abs() <= 1.0);
+        }
+        assert_eq!(pool.token_amount.0, raw_pool.token_amount.0);
+        assert!(raw_pool.gas_optimized_swap(0).is_err());
+    }
+
+    #[test]
+    fn test_effective_slippage_increases_with_size() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let small = pool.effective_slippage(1.0).unwrap();
+        let medium = pool.effective_slippage(20.0).unwrap();
+        let large = pool.effective_slippage(50.0).unwrap();
+
+        // Swaps staying above the liquidity target only pay the 0.1% minimum fee
+        assert!((small - 10.0).abs() < 1e-6);
+        assert!(small < medium);
+        assert!(medium < large);
+        assert!(pool.effective_slippage(0.0).is_err());
+    }
+
+    #[test]
+    fn test_validate_swap_inputs() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        for invalid in [f64::NAN, f64::INFINITY, -1.0, 0.0, u64::MAX as f64] {
+            assert!(pool.validate_swap_inputs(invalid).is_err());
+            assert!(pool.estimate_swap_output(invalid).is_err());
+            assert!(pool.swap(invalid, 0.0).is_err());
+        }
+        assert!(pool.validate_swap_inputs(6.0).is_ok());
+    }
+
+    #[test]
+    fn test_compute_optimal_fee_for_volume() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let (min_fee, max_fee) = pool.compute_optimal_fee_for_volume(50.0, 18.25).unwrap();
+        assert!((min_fee - 0.05).abs() < 1e-12);
+        assert!((max_fee - 0.15).abs() < 1e-12);
+
+        // Unachievable APR for such a low volume
+        assert!(pool.compute_optimal_fee_for_volume(0.01, 100.0).is_err());
+        assert!(pool.compute_optimal_fee_for_volume(0.0, 10.0).is_err());
+    }
+
+    #[test]
+    fn test_liquidity_score() {
+        let mut pool = LpPool::init(
+            1.5,
+            90.0,
+            1.0,
+            1.5,
+            FeeModel::Linear,
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        assert_eq!(pool.liquidity_score(), 0.0);
+
+        pool.add_liquidity(100.0).unwrap();
+        assert!(pool.liquidity_score() > 0.5);
+
+        // A wide fee range scores lower at the same depth
+        let mut wide_fee_pool = setup_pool();
+        wide_fee_pool.add_liquidity(100.0).unwrap();
+        assert!(wide_fee_pool.liquidity_score() < pool.liquidity_score());
+    }
+
+    #[test]
+    fn test_simulate_exit_impact() {
+        let mut pool = setup_pool();
+        // An amount rounding to zero raw LP tokens or an empty pool has no exit to simulate
+        assert!(matches!(
+            pool.simulate_exit_impact(1e-9),
+            Err(LpPoolError::InvalidTokenAmount)
+        ));
+        assert!(matches!(
+            pool.simulate_exit_impact(1.0),
+            Err(LpPoolError::InsufficientLiquidity)
+        ));
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        let impact = pool.simulate_exit_impact(50.0).unwrap();
+        assert!((impact.new_tvl - 50.0045).abs() < 1e-6);
+        assert!(impact.utilization_change < 0.0);
+        assert_eq!(impact.lp_fraction_exiting, 0.5);
+
+        // Only the locked LP tokens stay behind
+        let impact = pool.simulate_exit_impact(99.999).unwrap();
+        assert!((impact.new_tvl - 0.001).abs() < 1e-5);
+        assert_eq!(impact.new_fee_at_utilization, 9.0);
+        assert_eq!(impact.lp_fraction_exiting, 0.99999);
+
+        assert!(pool.simulate_exit_impact(100.0).is_err());
+        assert_eq!(pool.lp_token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+    }
+
+    #[test]
+    fn test_compute_swap_path_cost() {
+        let mut pools = vec![setup_pool(), setup_pool(), setup_pool()];
+        for pool in pools.iter_mut() {
+            pool.add_liquidity(100.0).unwrap();
+        }
+        let pool_refs: Vec<&LpPool> = pools.iter().collect();
+        let amounts = [6.0, 6.0, 6.0];
+
+        let total_cost = LpPool::compute_swap_path_cost(&pool_refs, &amounts).unwrap();
+        for pool in &pools {
+            let single_cost = LpPool::compute_swap_path_cost(&[pool], &[6.0]).unwrap();
+            assert!((single_cost - 0.009).abs() < 1e-9);
+            assert!(total_cost > single_cost);
+        }
+        assert!(LpPool::compute_swap_path_cost(&pool_refs, &[6.0]).is_err());
+    }
+
+    #[test]
+    fn test_reserve_snapshot_ring() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        assert_eq!(pool.reserve_snapshot_ring().len(), 1);
+
+        let start = (LpPool::now() / SECONDS_PER_HOUR + 1) * SECONDS_PER_HOUR;
+        pool.record_reserve_snapshot(start);
+        // Same hour, not recorded
+        pool.record_reserve_snapshot(start + 60);
+        assert_eq!(pool.reserve_snapshot_ring().len(), 2);
+
+        for hour in 1..30 {
+            pool.swap(0.1, 0.0).unwrap();
+            pool.record_reserve_snapshot(start + hour * SECONDS_PER_HOUR);
+        }
+
+        let snapshots = pool.reserve_snapshot_ring();
+        assert_eq!(snapshots.len(), RESERVE_RING_SIZE);
+        assert!(snapshots
+            .windows(2)
+            .all(|w| w[0].timestamp < w[1].timestamp));
+        let last = snapshots.last().unwrap();
+        assert_eq!(last.timestamp, start + 29 * SECONDS_PER_HOUR);
+        assert_eq!(last.token, pool.token_amount.0);
+        assert_eq!(last.st_token, pool.st_token_amount.0);
+    }
+
+    #[test]
+    fn test_cross_pool_arbitrage_profit() {
+        let mut pool_a = setup_pool();
+        pool_a.add_liquidity(100.0).unwrap();
+        let pool_at = |price: f64| {
+            let mut pool = LpPool::init(
+                price,
+                90.0,
+                0.1,
+                9.0,
+                FeeModel::Linear,
+                0.0,
+                DEFAULT_PRECISION_FACTOR,
+            )
+            .unwrap();
+            pool.add_liquidity(100.0).unwrap();
+            pool
+        };
+        let pool_b = pool_at(1.52);
+
+        // The cheaper pool has no staked tokens to sell yet
+        assert_eq!(
+            cross_pool_arbitrage_profit(&pool_a, &pool_b, 1.0),
+            f64::NEG_INFINITY
+        );
+        pool_a.swap(6.0, 0.0).unwrap();
+
+        // ~1.3% price differential exceeds the combined 0.2% fee
+        assert!(cross_pool_arbitrage_profit(&pool_a, &pool_b, 1.0) > 0.0);
+        assert_eq!(
+            cross_pool_arbitrage_profit(&pool_a, &pool_b, 1.0),
+            cross_pool_arbitrage_profit(&pool_b, &pool_a, 1.0)
+        );
+
+        // Both legs pay the 0.1% minimum fee: 1 staked token costs 1.501501 tokens after
+        // rounding, which a sale at 1.503005 pays back exactly
+        assert!(cross_pool_arbitrage_profit(&pool_a, &pool_at(1.503004), 1.0) < 0.0);
+        assert!(cross_pool_arbitrage_profit(&pool_a, &pool_at(1.503005), 1.0).abs() < 1e-9);
+        assert!(cross_pool_arbitrage_profit(&pool_a, &pool_at(1.503006), 1.0) > 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "toml")]
+    fn test_toml_round_trip() {
+        let mut pool = setup_pool();
+        pool.set_rebalance_threshold(0.2).unwrap();
+        pool.set_max_price_deviation(500).unwrap();
+
+        let toml = pool.to_toml();
+        assert!(toml.contains("[pool]"));
+        assert!(toml.contains("[pool.fees]"));
+        assert!(toml.contains("price = 1.5"));
+        assert!(toml.contains("rebalance_threshold = 0.2"));
+
+        let mut restored = LpPool::from_toml(&toml).unwrap();
+        assert_eq!(restored.to_toml(), toml);
+        assert_eq!(restored.price.0, pool.price.0);
+        assert_eq!(restored.liquidity_target.0, pool.liquidity_target.0);
+        assert_eq!(restored.min_fee.0, pool.min_fee.0);
+        assert_eq!(restored.max_fee.0, pool.max_fee.0);
+        assert_eq!(restored.rebalance_threshold, pool.rebalance_threshold);
+        assert_eq!(restored.max_price_deviation_bps, Some(500));
+        assert_eq!(restored.anti_snipe_blocks, 0);
+
+        // Only the configuration is stored, so both pools behave the same from empty
+        assert_eq!(
+            restored.add_liquidity(100.0).unwrap(),
+            pool.add_liquidity(100.0).unwrap()
+        );
+        assert_eq!(
+            restored.swap(30.0, 0.0).unwrap(),
+            pool.swap(30.0, 0.0).unwrap()
+        );
+        assert_eq!(restored.token_amount.0, pool.token_amount.0);
+        assert_eq!(restored.st_token_amount.0, pool.st_token_amount.0);
+        assert_eq!(pool.to_toml(), toml);
+        assert!(!toml.contains("token_amount"));
+
+        pool.fee_model = FeeModel::exponential(2.0);
+        let restored = LpPool::from_toml(&pool.to_toml()).unwrap();
+        assert_eq!(restored.fee_model, FeeModel::exponential(2.0));
+
+        let out_of_range = toml.replace("max_fee = 9.0", "max_fee = 150.0");
+        assert!(LpPool::from_toml(&out_of_range).is_err());
+        let negative = toml.replace("price = 1.5", "price = -1.5");
+        assert!(LpPool::from_toml(&negative).is_err());
+        let threshold = toml.replace("rebalance_threshold = 0.2", "rebalance_threshold = 0.7");
+        assert!(LpPool::from_toml(&threshold).is_err());
+        let coarse = toml.replace("precision_factor = 1000000", "precision_factor = 1024");
+        assert_eq!(LpPool::from_toml(&coarse).unwrap().precision_factor, 1024);
+        let decimal = toml.replace("precision_factor = 1000000", "precision_factor = 1000");
+        assert!(LpPool::from_toml(&decimal).is_err());
+        let anti_snipe = toml.replace("anti_snipe_blocks = 0", "anti_snipe_blocks = 10");
+        let restored = LpPool::from_toml(&anti_snipe).unwrap();
+        assert_eq!(restored.anti_snipe_blocks, 10);
+        assert!(LpPool::from_toml("[pool]").is_err());
+    }
+
+    #[test]
+    fn test_derive_synthetic_price() {
+        // init leaves the pool empty, so there is no reserve ratio to match the configured
+        // price until staked tokens are in the pool
+        let mut pool = setup_pool();
+        assert_eq!(pool.derive_synthetic_price(), f64::INFINITY);
+        pool.add_liquidity(100.0).unwrap();
+        assert_eq!(pool.derive_synthetic_price(), f64::INFINITY);
+
+        // A balanced pool matches the configured price again
+        pool.swap(6.0, 0.0).unwrap();
+        pool.rebalance().unwrap();
+        assert!((pool.derive_synthetic_price() - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_compute_equilibrium_trade() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let (direction, amount) = pool.compute_equilibrium_trade(1.45).unwrap();
+        assert_eq!(direction, SwapDirection::StakedToToken);
+        assert!((pool.effective_price_after_fees(amount).unwrap() - 1.45).abs() < 1e-5);
+        let tokens_received = pool.swap(amount, 0.0).unwrap();
+        assert!((tokens_received / amount - 1.45).abs() < 1e-5);
+
+        let (direction, amount) = pool.compute_equilibrium_trade(1.47).unwrap();
+        assert_eq!(direction, SwapDirection::TokenToStaked);
+        pool.add_liquidity(amount).unwrap();
+        assert!((pool.arbitrage_free_price() - 1.47).abs() < 1e-5);
+
+        // Above the price after the minimum fee
+        assert!(pool.compute_equilibrium_trade(1.5).is_err());
+    }
+
+    #[test]
+    fn test_position_pnl() {
+        let mut pool = LpPool::init(
+            1.0,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::Linear,
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(10.0, 0.0).unwrap();
+
+        let fees_before_deposit = pool.cumulative_fee_token.0;
+        let lp_amount = pool.add_liquidity(100.0).unwrap();
+        let position = LiquidityPosition {
+            lp_amount,
+            deposit_value: 100.0,
+            deposit_price: 1.0,
+            deposit_time: 0,
+            deposit_cumulative_fee: fees_before_deposit as f64 / DEFAULT_PRECISION_FACTOR as f64,
+        };
+        pool.swap(30.0, 0.0).unwrap();
+        pool.price.0 = (1.5 * DEFAULT_PRECISION_FACTOR as f64).round() as u64;
+
+        let pnl = pool.position_pnl(&position, 2 * SECONDS_PER_DAY);
+        assert!((pnl.impermanent_loss - 2.0204).abs() < 1e-4);
+        // Only the fees of the swap after the deposit count, at the position's LP share
+        let fees_since_deposit = (pool.cumulative_fee_token.0 - fees_before_deposit) as f64
+            / DEFAULT_PRECISION_FACTOR as f64;
+        let lp_share = lp_amount * DEFAULT_PRECISION_FACTOR as f64 / pool.lp_token_amount.0 as f64;
+        assert!(fees_since_deposit > 0.0);
+        assert!((pnl.fee_earnings - fees_since_deposit * lp_share).abs() < 1e-9);
+        assert!((pnl.net_pnl - (pnl.fee_earnings - pnl.impermanent_loss)).abs() < 1e-12);
+        assert_eq!(pnl.holding_days, 2.0);
+    }
+
+    #[test]
+    fn test_market_depth_curve() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let curve = pool.market_depth_curve(11);
+        assert_eq!(curve.len(), 11);
+        assert!((curve[0].0 - 1.425).abs() < 1e-9);
+        assert!((curve[10].0 - 1.575).abs() < 1e-9);
+        assert!(curve
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 && w[0].1 <= w[1].1));
+        assert!(curve[10].1 > 0.0);
+        assert!(pool.market_depth_curve(0).is_empty());
+    }
+
+    #[test]
+    fn test_compute_tvl_raw() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        // 91.009 tokens + 6 staked tokens at 1.5
+        assert_eq!(pool.compute_tvl_raw(), 100_009_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "pool value dropped")]
+    #[cfg(debug_assertions)]
+    fn test_debug_assert_tvl_fires_on_inconsistent_state() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let old_tvl = pool.compute_tvl_raw();
+        pool.st_token_amount.0 = 0;
+        pool.token_amount.0 -= 1_000;
+        pool.debug_assert_tvl(old_tvl, 999);
+    }
+
+    #[test]
+    fn test_io_error_from_lp_pool_error() {
+        use std::io::{ErrorKind, Read, Write};
+
+        fn write_pool(pool: &LpPool, writer: &mut impl Write) -> std::io::Result<()> {
+            for value in [
+                pool.price.0,
+                pool.liquidity_target.0,
+                pool.min_fee.0,
+                pool.max_fee.0,
+                pool.token_amount.0,
+            ] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            Ok(())
+        }
+
+        fn read_pool(reader: &mut impl Read) -> std::io::Result<LpPool> {
+            let mut values = [0_u64; 5];
+            for value in values.iter_mut() {
+                let mut bytes = [0_u8; 8];
+                reader.read_exact(&mut bytes)?;
+                *value = u64::from_le_bytes(bytes);
+            }
+            let to_f64 = |raw: u64| raw as f64 / DEFAULT_PRECISION_FACTOR as f64;
+            let mut pool = LpPool::init(
+                to_f64(values[0]),
+                to_f64(values[1]),
+                to_f64(values[2]) * 100.0,
+                to_f64(values[3]) * 100.0,
+                FeeModel::Linear,
+                0.0,
+                DEFAULT_PRECISION_FACTOR,
+            )?;
+            pool.add_liquidity(to_f64(values[4]))?;
+            Ok(pool)
+        }
+
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let mut buffer: Vec<u8> = Vec::new();
+        write_pool(&pool, &mut buffer).unwrap();
+        let restored = read_pool(&mut buffer.as_slice()).unwrap();
+        assert_eq!(restored.price.0, pool.price.0);
+        assert_eq!(restored.min_fee.0, pool.min_fee.0);
+        assert_eq!(restored.token_amount.0, pool.token_amount.0);
+
+        // A zero liquidity target is rejected by `init`, the error surfaces as `io::Error`
+        let mut invalid_pool = setup_pool();
+        invalid_pool.liquidity_target.0 = 0;
+        let mut buffer: Vec<u8> = Vec::new();
+        write_pool(&invalid_pool, &mut buffer).unwrap();
+        let error = read_pool(&mut buffer.as_slice()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        let error: std::io::Error = LpPoolError::InvalidConfig.into();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_compute_rebalancing_cost() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        pool.rebalance().unwrap();
+
+        assert_eq!(
+            pool.compute_rebalancing_cost(pool.reserve_ratio()).unwrap(),
+            0.0
+        );
+
+        let staked_in = pool.optimal_swap_amount(0.4).unwrap();
+        assert!(pool.compute_rebalancing_cost(0.4).unwrap() > 0.0);
+        pool.swap(staked_in, 0.0).unwrap();
+        assert!((pool.reserve_ratio() - 0.4).abs() < 1e-6);
+
+        // Swapping staked tokens in cannot raise the ratio
+        assert!(pool.compute_rebalancing_cost(0.5).is_err());
+    }
+
+    #[test]
+    fn test_enforce_min_liquidity_constant() {
+        let mut pool = setup_pool();
+        assert!(pool.enforce_min_liquidity_constant().is_err());
+        pool.add_liquidity(50.0).unwrap();
+        assert!(pool.enforce_min_liquidity_constant().is_ok());
+
+        // The locked LP tokens keep MINIMUM_LIQUIDITY in the pool
+        pool.remove_liquidity(49.999).unwrap();
+        assert_eq!(pool.token_amount.0, MINIMUM_LIQUIDITY);
+        assert!(pool.enforce_min_liquidity_constant().is_ok());
+        assert!(pool.remove_liquidity(0.0001).is_err());
+
+        pool.reset_to_initial().unwrap();
+        assert!(pool.enforce_min_liquidity_constant().is_err());
+        assert!(pool.swap(0.0001, 0.0).is_err());
+
+        pool.add_liquidity(10.0).unwrap();
+        assert!(pool.swap(0.0001, 0.0).is_ok());
+    }
+
+    #[test]
+    fn test_swap_with_referral() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let mut referral_pool = pool.clone();
+
+        assert_eq!(
+            referral_pool.swap_with_referral(6.0, 0).unwrap(),
+            (8.991, 0.0)
+        );
+
+        let (user_received, referrer_fee) = pool.swap_with_referral(6.0, 500).unwrap();
+        assert_eq!(referrer_fee, 0.44955);
+        assert_eq!(user_received, 8.54145);
+        // The referral fee does not come out of the pool
+        assert_eq!(pool.token_amount.0, referral_pool.token_amount.0);
+
+        assert!(pool.swap_with_referral(6.0, 501).is_err());
+
+        // The referral fee of a large swap does not overflow
+        let mut pool = setup_pool();
+        pool.add_liquidity(1e12).unwrap();
+        let (user_received, referrer_fee) = pool.swap_with_referral(1e11, 500).unwrap();
+        assert!((referrer_fee / (user_received + referrer_fee) - 0.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_internal_price_check() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.set_max_price_deviation(100).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        assert!(matches!(
+            pool.internal_price_check(100),
+            Err(LpPoolError::PriceDeviation { configured, .. }) if configured == 1.5
+        ));
+        assert!(pool.swap(1.0, 0.0).is_err());
+
+        pool.rebalance().unwrap();
+        assert!(pool.internal_price_check(100).is_ok());
+        assert!(pool.swap(0.1, 0.0).is_ok());
+    }
+
+    #[test]
+    fn test_compute_lp_fee_share() {
+        let mut pool = setup_pool();
+        assert!(matches!(
+            pool.compute_lp_fee_share(1e-9),
+            Err(LpPoolError::InvalidTokenAmount)
+        ));
+        assert!(matches!(
+            pool.compute_lp_fee_share(1.0),
+            Err(LpPoolError::InsufficientLiquidity)
+        ));
+        let first_lp = pool.add_liquidity(75.0).unwrap();
+        let second_lp = pool.add_liquidity(25.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        assert_eq!(pool.cumulative_fee_token.0, 9_000);
+        // The locked LP tokens hold the rest of the first deposit's share
+        assert_eq!(pool.compute_lp_fee_share(first_lp).unwrap(), 0.006749);
+        assert_eq!(pool.compute_lp_fee_share(second_lp).unwrap(), 0.00225);
+        assert!(pool
+            .compute_lp_fee_share(first_lp + second_lp + 1.0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_anti_snipe_delay() {
+        let mut pool = LpPool::init_with_anti_snipe(
+            1.5,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::Linear,
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+            10,
+        )
+        .unwrap();
+        pool.add_liquidity(100.0).unwrap();
+
+        assert!(matches!(
+            pool.swap(6.0, 0.0),
+            Err(LpPoolError::PoolNotYetActive)
+        ));
+        let launch = pool.launch_timestamp;
+        assert!(pool
+            .anti_snipe_delay(launch + 9 * BLOCK_TIME_SECONDS)
+            .is_err());
+        assert!(pool
+            .anti_snipe_delay(launch + 10 * BLOCK_TIME_SECONDS)
+            .is_ok());
+
+        pool.launch_timestamp -= 10 * BLOCK_TIME_SECONDS;
+        assert_eq!(pool.swap(6.0, 0.0).unwrap(), 8.991);
+
+        assert_eq!(setup_pool().anti_snipe_blocks, 0);
+    }
+
+    #[test]
+    fn test_compute_vwap() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let now = LpPool::now();
+        assert!(matches!(
+            pool.compute_vwap(SECONDS_PER_HOUR, now),
+            Err(LpPoolError::InsufficientData)
+        ));
+
+        pool.swap(6.0, 0.0).unwrap();
+        pool.add_liquidity(10.0).unwrap();
+        pool.swap(30.0, 0.0).unwrap();
+
+        let now = LpPool::now();
+        let expected = (8.991 + 43.44237) / (9.0 + 45.0) * 1.5;
+        assert!((pool.compute_vwap(SECONDS_PER_HOUR, now).unwrap() - expected).abs() < 1e-6);
+
+        // Both swaps are out of a window ending two hours later
+        assert!(pool
+            .compute_vwap(SECONDS_PER_HOUR, now + 2 * SECONDS_PER_HOUR)
+            .is_err());
+    }
+
+    #[test]
+    fn test_maximum_loss_scenario() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        let scenario = pool.maximum_loss_scenario((1.0, 6.0));
+        // The price quadruples at the top of the range
+        assert!((scenario.max_il - 0.2).abs() < 1e-9);
+        assert!((scenario.max_il_price - 6.0).abs() < 1e-9);
+        assert_eq!(scenario.worst_price, 1.0);
+        assert!((scenario.pool_value_at_worst - 97.009).abs() < 1e-9);
+
+        let scenario = pool.maximum_loss_scenario((1.5, 1.5));
+        assert_eq!(scenario.max_il, 0.0);
+    }
+
+    #[test]
+    fn test_instruction_round_trip() {
+        for op in [
+            PoolOp::AddLiquidity(100.0),
+            PoolOp::Swap(6.0),
+            PoolOp::RemoveLiquidity(9.9991),
+        ] {
+            let data = LpPool::encode_instruction(op);
+            assert_eq!(data.len(), 9);
+            assert_eq!(LpPool::decode_instruction(&data).unwrap(), op);
+        }
+
+        let data = LpPool::encode_instruction(PoolOp::Swap(1.5));
+        assert_eq!(data, [0x02, 0x60, 0xe3, 0x16, 0, 0, 0, 0, 0]);
+
+        assert!(LpPool::decode_instruction(&[]).is_err());
+        assert!(LpPool::decode_instruction(&data[..8]).is_err());
+        assert!(LpPool::decode_instruction(&[0x04, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_expected_fee_revenue_distribution() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        assert_eq!(pool.fee_revenue().0, 0.009);
+
+        let holders = [(1, 50.0), (2, 30.0), (3, 20.0)];
+        let distribution = pool.expected_fee_revenue_distribution(&holders).unwrap();
+        let expected = [0.0045, 0.0027, 0.0018];
+        for (share, expected) in distribution.iter().zip(expected) {
+            assert!((share - expected).abs() < 1e-12);
+        }
+
+        assert!(pool
+            .expected_fee_revenue_distribution(&[(1, 50.0), (2, 30.0)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_price_oracle_update() {
+        let mut pool = setup_pool();
+
+        pool.apply_price_oracle_update(1.6, 0.008, 0.01).unwrap();
+        assert_eq!(pool.price.0, 1_600_000);
+
+        assert!(matches!(
+            pool.apply_price_oracle_update(1.7, 0.017, 0.01),
+            Err(LpPoolError::LowOracleConfidence { confidence, price })
+                if confidence == 0.017 && price == 1.7
+        ));
+        assert!(pool.apply_price_oracle_update(0.0, 0.0, 0.01).is_err());
+        assert_eq!(pool.price.0, 1_600_000);
+    }
+
+    #[test]
+    fn test_compute_realized_pnl_for_lp() {
+        let mut pool = setup_pool();
+        let lp_held = pool.add_liquidity(100.0).unwrap();
+        let entry_snapshot = pool.pool_snapshot();
+        assert_eq!(
+            pool.compute_realized_pnl_for_lp(&entry_snapshot, lp_held),
+            0.0
+        );
+
+        pool.swap(6.0, 0.0).unwrap();
+        let pnl = pool.compute_realized_pnl_for_lp(&entry_snapshot, lp_held);
+        // 99.999 of the 100 LP tokens, the rest is locked
+        assert!((pnl - 0.00899991).abs() < 1e-9);
+
+        // Half of the position earned half of the fees
+        let pnl = pool.compute_realized_pnl_for_lp(&entry_snapshot, lp_held / 2.0);
+        assert!((pnl - 0.004499955).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_compute_min_staked_for_target_price() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let staked_in = pool.compute_min_staked_for_target_price(1.45).unwrap();
+        assert!((pool.price_after_swap(staked_in).unwrap() - 1.45).abs() < 1e-5);
+        pool.swap(staked_in, 0.0).unwrap();
+        assert!((pool.arbitrage_free_price() - 1.45).abs() < 1e-5);
+
+        assert!(matches!(
+            pool.compute_min_staked_for_target_price(1.49),
+            Err(LpPoolError::InvalidPrice)
+        ));
+        // Below the price after the maximum fee
+        assert!(pool.compute_min_staked_for_target_price(1.3).is_err());
+    }
+
+    #[test]
+    fn test_add_liquidity_idempotent() {
+        let mut pool = setup_pool();
+
+        assert_eq!(pool.add_liquidity_idempotent(100.0, 1).unwrap(), 99.999);
+        assert_eq!(pool.add_liquidity_idempotent(100.0, 1).unwrap(), 99.999);
+        assert_eq!(pool.token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+
+        assert_eq!(pool.add_liquidity_idempotent(50.0, 2).unwrap(), 50.0);
+        assert_eq!(pool.token_amount.0, 150 * DEFAULT_PRECISION_FACTOR);
+
+        // A failed request does not consume its nonce
+        assert!(pool.add_liquidity_idempotent(0.0, 3).is_err());
+        assert_eq!(pool.add_liquidity_idempotent(10.0, 3).unwrap(), 10.0);
+    }
+
+    #[test]
+    fn test_compute_maximum_swap_for_bounded_impact() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let staked_in = pool.compute_maximum_swap_for_bounded_impact(3.0).unwrap();
+        assert!(pool.price_impact(staked_in).unwrap() <= 3.0);
+        assert!(pool.price_impact(staked_in + 0.01).unwrap() > 3.0);
+
+        assert_eq!(
+            pool.compute_maximum_swap_for_bounded_impact(100.0).unwrap(),
+            pool.max_swap_in()
+        );
+        assert_eq!(
+            pool.compute_maximum_swap_for_bounded_impact(0.0).unwrap(),
+            0.0
+        );
+        assert!(pool.compute_maximum_swap_for_bounded_impact(-1.0).is_err());
+    }
+
+    #[test]
+    fn test_fee_income_per_lp_token() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        assert_eq!(pool.fee_income_per_lp_token(), 0.0);
+
+        let mut previous = 0.0;
+        for _ in 0..3 {
+            pool.swap(6.0, 0.0).unwrap();
+            assert!(pool.fee_income_per_lp_token() > previous);
+            previous = pool.fee_income_per_lp_token();
+        }
+
+        pool.reset_to_initial().unwrap();
+        assert_eq!(pool.fee_income_per_lp_token(), 0.0);
+        assert_eq!(pool.token_amount.0, 0);
+    }
+
+    #[test]
+    fn test_reset_to_initial() {
+        let mut pool = LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::Linear,
+            50.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        pool.set_rebalance_threshold(0.4).unwrap();
+        pool.set_max_price_deviation(500).unwrap();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        assert!(pool.protocol_fee_accrued.0 > 0);
+        pool.launch_timestamp = 0;
+
+        pool.reset_to_initial().unwrap();
+        assert_eq!(pool.pool_value(), 0);
+        assert_eq!(pool.lp_token_amount.0, 0);
+        assert_eq!(pool.locked_lp_tokens.0, 0);
+        assert_eq!(pool.protocol_fee_accrued.0, 0);
+        assert_eq!(pool.cumulative_fee_token.0, 0);
+        assert_eq!(pool.total_volume_swapped, 0);
+        assert_eq!(pool.total_fees_collected, 0);
+        assert!(pool.swap_history.is_empty());
+        assert!(pool.launch_timestamp > 0);
+        // The configuration survives the reset
+        assert_eq!(pool.liquidity_target.0, 90 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(pool.protocol_fee_share.0, DEFAULT_PRECISION_FACTOR / 2);
+        assert_eq!(pool.rebalance_threshold, Some(0.4));
+        assert_eq!(pool.max_price_deviation_bps, Some(500));
+    }
+
+    #[test]
+    fn test_compute_add_liquidity_worth() {
+        let mut pool = setup_pool();
+        let lp_received = pool.add_liquidity(100.0).unwrap();
+        assert_eq!(pool.compute_add_liquidity_worth(lp_received), 99.999);
+
+        // Fees accrue to the LP tokens
+        pool.swap(6.0, 0.0).unwrap();
+        assert!((pool.compute_add_liquidity_worth(lp_received) - 100.00799991).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_price_manipulation_resistance_score() {
+        let mut shallow_pool = LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            20.0,
+            FeeModel::Linear,
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        shallow_pool.add_liquidity(100.0).unwrap();
+        let mut deep_pool = LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            20.0,
+            FeeModel::Linear,
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        deep_pool.add_liquidity(1_000.0).unwrap();
+
+        let shallow_score = shallow_pool.price_manipulation_resistance_score();
+        let deep_score = deep_pool.price_manipulation_resistance_score();
+        assert!(shallow_score > 0.0 && shallow_score < 1.0);
+        assert!(deep_score > shallow_score);
+
+        // A 9% maximum fee cannot move the price by 10%
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        assert_eq!(pool.price_manipulation_resistance_score(), 1.0);
+    }
+
+    #[test]
+    fn test_account_data_round_trip() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        pool.set_rebalance_threshold(0.4).unwrap();
+
+        let data = pool.serialize_to_account_data();
+        assert_eq!(data[0], ACCOUNT_DISCRIMINATOR);
+        assert_eq!(data[1], ACCOUNT_VERSION);
+        assert_eq!(data[8..16], 1_500_000_u64.to_le_bytes());
+
+        let mut restored = LpPool::from_account_data(&data).unwrap();
+        assert_eq!(restored.serialize_to_account_data(), data);
+        assert_eq!(restored.rebalance_threshold, Some(0.4));
+        assert_eq!(restored.max_price_deviation_bps, None);
+        assert_eq!(
+            restored.swap(30.0, 0.0).unwrap(),
+            pool.swap(30.0, 0.0).unwrap()
+        );
+
+        let mut invalid = data;
+        invalid[1] = ACCOUNT_VERSION + 1;
+        assert!(LpPool::from_account_data(&invalid).is_err());
+        assert!(LpPool::from_account_data(&[0; ACCOUNT_DATA_SIZE]).is_err());
+
+        // The rebalance threshold at offset 88 must be in (0, 0.5) like in
+        // set_rebalance_threshold
+        for threshold in [0.7, -0.1, f64::NAN] {
+            let mut invalid = data;
+            invalid[88..96].copy_from_slice(&threshold.to_bits().to_le_bytes());
+            assert!(matches!(
+                LpPool::from_account_data(&invalid),
+                Err(LpPoolError::InvalidConfig)
+            ));
+        }
+
+        pool.fee_model = FeeModel::exponential(2.0);
+        let restored = LpPool::from_account_data(&pool.serialize_to_account_data()).unwrap();
+        assert_eq!(restored.fee_model, FeeModel::exponential(2.0));
+    }
 
     #[test]
-    fn test_story_example() {
+    fn test_with_zero_fee() {
         let mut pool = setup_pool();
-        let token_return = pool.add_liquidity(100.0).unwrap();
-        assert_eq!(token_return, 100.0);
-        let swap_return = pool.swap(6.0).unwrap();
-        let expected_tokens_received = 8.991;
-        assert!((swap_return - expected_tokens_received).abs() < 0.001);
-        let second_token_return = pool.add_liquidity(10.0).unwrap();
-        assert_eq!(second_token_return, 9.9991);
-        let second_swap_return = pool.swap(30.0).unwrap();
-        assert_eq!(second_swap_return, 43.44237);
-        // let (remove_token, staked_token) = pool.remove_liquidity(100.9991).unwrap();
-        // assert_eq!(remove_token, 57.56663);
-        // assert_eq!(staked_token, 36.0);
+        pool.add_liquidity(100.0).unwrap();
+
+        let mut zero_fee_pool = pool.with_zero_fee().unwrap();
+        assert_eq!(zero_fee_pool.swap(6.0, 0.0).unwrap(), 9.0);
+        assert_eq!(zero_fee_pool.swap(30.0, 0.0).unwrap(), 45.0);
+        assert_eq!(pool.max_fee.0, 90_000);
+
+        pool.with_fees_disabled().unwrap();
+        assert_eq!(pool.swap(6.0, 0.0).unwrap(), 9.0);
+    }
+
+    #[test]
+    fn test_compute_delta_neutral_hedge() {
+        let mut pool = setup_pool();
+        assert_eq!(pool.compute_delta_neutral_hedge(10.0), (0.0, 0.0));
+
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        pool.rebalance().unwrap();
+
+        // A quarter of the pool holds a quarter of each reserve
+        let tvl = pool.compute_tvl_raw() as f64 / DEFAULT_PRECISION_FACTOR as f64;
+        let (token_short, st_token_short) = pool.compute_delta_neutral_hedge(tvl / 4.0);
+        assert!((token_short - pool.token_amount.0 as f64 / 4e6).abs() < 1e-9);
+        assert!((st_token_short - pool.st_token_amount.0 as f64 / 4e6).abs() < 1e-9);
+        // The hedge is worth the position
+        assert!((token_short + st_token_short * 1.5 - tvl / 4.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_expected_divergence_loss() {
+        let pool = setup_pool();
+        assert_eq!(pool.expected_divergence_loss(1.5, 1.5), 0.0);
+        // A 4x price move loses 20% against holding
+        assert!((pool.expected_divergence_loss(1.5, 6.0) - 0.2).abs() < 1e-12);
+
+        for (initial_price, final_price) in [(1.5, 1.5), (1.5, 6.0), (1.5, 0.375), (2.0, 2.5)] {
+            let divergence_loss = pool.expected_divergence_loss(initial_price, final_price);
+            let impermanent_loss = pool.impermanent_loss(initial_price, final_price);
+            assert!((divergence_loss - impermanent_loss).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_validate_state_consistency() {
+        let mut pool = setup_pool();
+        assert!(pool.validate_state_consistency().is_ok());
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        assert!(pool.validate_state_consistency().is_ok());
+
+        pool.price = Price(0);
+        pool.min_fee = Percentage(DEFAULT_PRECISION_FACTOR / 2);
+        pool.max_fee = Percentage(DEFAULT_PRECISION_FACTOR / 4);
+        pool.lp_token_amount = LpTokenAmount(0);
+        let violations = pool.validate_state_consistency().unwrap_err();
+        assert_eq!(violations.len(), 3);
+        assert_eq!(violations[0], "price must be positive");
+        assert_eq!(violations[1], "reserves are not backed by any LP tokens");
+        assert_eq!(violations[2], "min fee 50% exceeds max fee 25%");
+    }
+
+    #[test]
+    fn test_add_liquidity_single_sided() {
+        let mut pool = setup_pool();
+        assert_eq!(pool.add_liquidity_single_sided(10.0).unwrap(), 14.999);
+        assert_eq!(pool.st_token_amount.0, 10 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(pool.pool_value(), 15 * DEFAULT_PRECISION_FACTOR as u128);
+        assert_eq!(pool.add_liquidity(15.0).unwrap(), 15.0);
+        assert!(pool.add_liquidity_single_sided(-1.0).is_err());
+    }
+
+    #[test]
+    fn test_try_swap_or_add_liquidity() {
+        // A deep pool stays at the minimum fee of 10 bps
+        let mut deep_pool = setup_pool();
+        deep_pool.add_liquidity(1000.0).unwrap();
+        assert_eq!(
+            deep_pool.try_swap_or_add_liquidity(6.0, 20).unwrap(),
+            (8.991, true)
+        );
+
+        // A shallow pool charges more than the budget
+        let mut shallow_pool = setup_pool();
+        shallow_pool.add_liquidity(10.0).unwrap();
+        let (lp_tokens, swapped) = shallow_pool.try_swap_or_add_liquidity(6.0, 20).unwrap();
+        assert!(!swapped);
+        assert_eq!(lp_tokens, 9.0);
+        assert_eq!(shallow_pool.token_amount.0, 10 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(shallow_pool.st_token_amount.0, 6 * DEFAULT_PRECISION_FACTOR);
+
+        // A swap the reserve cannot pay for is deposited as well
+        let mut empty_pool = setup_pool();
+        let (lp_tokens, swapped) = empty_pool.try_swap_or_add_liquidity(6.0, 10_000).unwrap();
+        assert!(!swapped);
+        assert_eq!(lp_tokens, 8.999);
+    }
+
+    #[test]
+    fn test_conversion_rates() {
+        let mut pool = setup_pool();
+        assert_eq!(pool.token_to_lp_conversion_rate(), 1.0);
+        assert_eq!(pool.lp_to_token_conversion_rate(), 1.0);
+
+        pool.add_liquidity(100.0).unwrap();
+        assert_eq!(pool.token_to_lp_conversion_rate(), 1.0);
+        assert_eq!(pool.lp_to_token_conversion_rate(), 1.0);
+
+        // Fees raise the value of each LP token
+        pool.swap(6.0, 0.0).unwrap();
+        assert!(pool.lp_to_token_conversion_rate() > 1.0);
+        assert!(
+            (pool.token_to_lp_conversion_rate() * pool.lp_to_token_conversion_rate() - 1.0).abs()
+                < 1e-12
+        );
+    }
+
+    #[test]
+    fn test_expected_fee_on_remove() {
+        let mut pool = setup_pool();
+        assert_eq!(pool.expected_fee_on_remove(0.0), 0.0);
+        assert_eq!(pool.expected_fee_on_remove(1.0), 0.0);
+        pool.add_liquidity(200.0).unwrap();
+        pool.swap(20.0, 0.0).unwrap();
+        let lp_supply = pool.lp_token_supply();
+        let token_amount = pool.token_amount.0 as f64 / DEFAULT_PRECISION_FACTOR as f64;
+        let st_token_amount = pool.st_token_amount.0 as f64 / DEFAULT_PRECISION_FACTOR as f64;
+
+        // Removing three quarters takes the token reserve below the target of 90
+        let fee = pool.expected_fee_on_remove(lp_supply * 0.75);
+        let fee_rate = 0.09 - 0.089 * token_amount * 0.25 / 90.0;
+        assert!((fee - st_token_amount * 0.75 * fee_rate * 1.5).abs() < 1e-3);
+
+        let gross_value = (token_amount + st_token_amount * 1.5) * 0.75;
+        let (tokens_received, staked_received) = pool.remove_liquidity(lp_supply * 0.75).unwrap();
+        let net_value = tokens_received + staked_received * 1.5;
+        assert!((gross_value - net_value - fee).abs() < 1e-5);
+
+        // The last provider out pays no fee
+        assert_eq!(pool.expected_fee_on_remove(pool.lp_token_supply()), 0.0);
+        assert_eq!(pool.expected_fee_on_remove(1000.0), 0.0);
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        let mut pool = setup_pool();
+        assert!(matches!(
+            pool.add_liquidity(1e14),
+            Err(LpPoolError::ArithmeticOverflow)
+        ));
+        pool.add_liquidity(1e13).unwrap();
+        assert!(matches!(
+            pool.add_liquidity(1e13),
+            Err(LpPoolError::ArithmeticOverflow)
+        ));
+        assert_eq!(
+            pool.token_amount.0,
+            10_000_000_000_000 * DEFAULT_PRECISION_FACTOR
+        );
+        assert!(matches!(
+            pool.add_liquidity_single_sided(1e13),
+            Err(LpPoolError::ArithmeticOverflow)
+        ));
+        assert!(matches!(
+            pool.remove_liquidity(f64::NAN),
+            Err(LpPoolError::InvalidTokenAmount)
+        ));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_pool_operations_never_panic(
+            price in 1e-6_f64..1e9,
+            ops in proptest::collection::vec((0_u8..5, 1e-6_f64..1e15), 1..20),
+        ) {
+            let mut pool = LpPool::init(
+                price,
+                90.0,
+                0.1,
+                9.0,
+                FeeModel::Linear,
+                0.0,
+                DEFAULT_PRECISION_FACTOR,
+            )
+            .unwrap();
+            for (op, amount) in ops {
+                let result = match op {
+                    0 => pool.add_liquidity(amount).map(|_| ()),
+                    1 => pool.swap(amount, 0.0).map(|_| ()),
+                    2 => pool.add_liquidity_single_sided(amount).map(|_| ()),
+                    3 => pool.swap_tokens_for_staked(amount, 0.0).map(|_| ()),
+                    _ => pool
+                        .remove_liquidity(amount.min(pool.lp_token_supply()))
+                        .map(|_| ()),
+                };
+                proptest::prop_assert!(matches!(
+                    result,
+                    Ok(())
+                        | Err(LpPoolError::InvalidTokenAmount)
+                        | Err(LpPoolError::InsufficientLiquidity)
+                        | Err(LpPoolError::InsufficientStakedTokens)
+                        | Err(LpPoolError::ArithmeticOverflow)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_swap_min_tokens_out() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        assert!(matches!(
+            pool.swap(6.0, 8.9911),
+            Err(LpPoolError::SlippageExceeded { .. })
+        ));
+        assert_eq!(pool.token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(pool.clone().swap(6.0, 8.9909).unwrap(), 8.991);
+        assert_eq!(pool.swap(6.0, 8.991).unwrap(), 8.991);
+    }
+
+    #[test]
+    fn test_swap_tokens_for_staked() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        assert!(matches!(
+            pool.swap_tokens_for_staked(1.5, 0.0),
+            Err(LpPoolError::InsufficientStakedTokens)
+        ));
+
+        // Round trip: both legs stay at or above the liquidity target and pay the min fee
+        let tokens_received = pool.swap(6.0, 0.0).unwrap();
+        assert_eq!(tokens_received, 8.991);
+        assert!(matches!(
+            pool.clone().swap_tokens_for_staked(tokens_received, 5.989),
+            Err(LpPoolError::SlippageExceeded { .. })
+        ));
+        let st_received = pool.swap_tokens_for_staked(tokens_received, 5.988).unwrap();
+        assert!((st_received - 6.0 * 0.999 * 0.999).abs() < 1e-6);
+        assert_eq!(pool.token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+        assert!(pool.st_token_amount.0 > 0);
+    }
+
+    #[test]
+    fn test_estimate_swap() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let estimate = pool.estimate_swap(6.0).unwrap();
+        assert_eq!(estimate.fee_rate, 0.001);
+        assert_eq!(estimate.price_impact_bps, 10);
+        assert!((estimate.effective_price - 1.4985).abs() < 1e-12);
+        assert_eq!(pool.token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(pool.swap(6.0, 0.0).unwrap(), estimate.tokens_out);
+
+        // Below the liquidity target the fee rises with the swap size
+        let estimate = pool.estimate_swap(30.0).unwrap();
+        assert!(estimate.fee_rate > 0.001);
+        assert_eq!(pool.swap(30.0, 0.0).unwrap(), estimate.tokens_out);
+
+        let estimate = pool.estimate_swap_tokens_for_staked(15.0).unwrap();
+        assert!(estimate.price_impact_bps > 10);
+        assert_eq!(
+            pool.swap_tokens_for_staked(15.0, 0.0).unwrap(),
+            estimate.tokens_out
+        );
+        assert!(pool.estimate_swap(-1.0).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_roundtrip() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        pool.add_liquidity(10.0).unwrap();
+
+        let json = serde_json::to_string(&pool).unwrap();
+        assert!(json.contains("\"price\":1500000"));
+        let mut restored: LpPool = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.price.0, pool.price.0);
+        assert_eq!(restored.token_amount.0, pool.token_amount.0);
+        assert_eq!(restored.st_token_amount.0, pool.st_token_amount.0);
+        assert_eq!(restored.lp_token_amount.0, pool.lp_token_amount.0);
+        assert_eq!(restored.liquidity_target.0, pool.liquidity_target.0);
+        assert_eq!(restored.min_fee.0, pool.min_fee.0);
+        assert_eq!(restored.max_fee.0, pool.max_fee.0);
+        assert_eq!(restored.cumulative_fee_token.0, pool.cumulative_fee_token.0);
+        assert_eq!(restored.total_volume_swapped, pool.total_volume_swapped);
+        assert_eq!(restored.total_fees_collected, pool.total_fees_collected);
+        assert_eq!(restored.launch_timestamp, pool.launch_timestamp);
+        assert!(restored.reserve_ring.iter().eq(pool.reserve_ring.iter()));
+        assert!(restored.swap_history.iter().eq(pool.swap_history.iter()));
+        assert_eq!(
+            restored.swap(30.0, 0.0).unwrap(),
+            pool.swap(30.0, 0.0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_stats() {
+        let mut pool = setup_pool();
+        let stats = pool.get_stats();
+        assert_eq!(stats.tvl_tokens, 0.0);
+        assert_eq!(stats.depletion_ratio, 1.0);
+        assert_eq!(stats.current_fee_rate, 0.09);
+        assert_eq!(stats.lp_token_price, 1.0);
+
+        pool.add_liquidity(100.0).unwrap();
+        let tokens_received = pool.swap(6.0, 0.0).unwrap() + pool.swap(30.0, 0.0).unwrap();
+        let stats = pool.get_stats();
+        let expected_tvl = 100.0 - tokens_received + 36.0 * 1.5;
+        assert!((stats.tvl_tokens - expected_tvl).abs() < 1e-9);
+        assert!((stats.depletion_ratio - (1.0 - (100.0 - tokens_received) / 90.0)).abs() < 1e-9);
+        assert_eq!(stats.depletion_ratio, 1.0 - pool.utilization_ratio());
+        assert!(stats.current_fee_rate > 0.001 && stats.current_fee_rate < 0.09);
+        assert!((stats.lp_token_price - expected_tvl / 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn invariants_hold_after_round_trip() {
+        let mut pool = setup_pool();
+        assert!(pool.check_invariants().is_ok());
+        pool.add_liquidity(100.0).unwrap();
+        assert!(pool.check_invariants().is_ok());
+        pool.swap(6.0, 0.0).unwrap();
+        assert!(pool.check_invariants().is_ok());
+        pool.add_liquidity(10.0).unwrap();
+        assert!(pool.check_invariants().is_ok());
+        pool.swap(30.0, 0.0).unwrap();
+        assert!(pool.check_invariants().is_ok());
+        pool.remove_liquidity(109.9981).unwrap();
+        assert!(pool.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_check_invariants_violations() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.token_amount = TokenAmount(0);
+        pool.min_fee = Percentage(DEFAULT_PRECISION_FACTOR / 2);
+        pool.max_fee = Percentage(DEFAULT_PRECISION_FACTOR * 2);
+        pool.liquidity_target = TokenAmount(0);
+
+        assert_eq!(
+            pool.check_invariants().unwrap_err(),
+            vec![
+                InvariantViolation::LpSupplyExceedsTvl {
+                    lp_supply: 100 * DEFAULT_PRECISION_FACTOR,
+                    tvl: 0
+                },
+                InvariantViolation::FeeOutOfRange {
+                    min_fee: DEFAULT_PRECISION_FACTOR / 2,
+                    max_fee: DEFAULT_PRECISION_FACTOR * 2
+                },
+                InvariantViolation::ZeroLiquidityTarget,
+            ]
+        );
+
+        let mut pool = setup_pool();
+        pool.token_amount = TokenAmount(DEFAULT_PRECISION_FACTOR);
+        assert_eq!(
+            pool.check_invariants().unwrap_err(),
+            vec![InvariantViolation::OrphanedReserves {
+                tvl: DEFAULT_PRECISION_FACTOR as u128
+            }]
+        );
+
+        // Reserves worth less than the LP supply after a price drop
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(60.0, 0.0).unwrap();
+        assert!(pool.check_invariants().is_ok());
+        pool.price = Price(DEFAULT_PRECISION_FACTOR / 2);
+        pool.rebalance_threshold = Some(0.5);
+        let tvl = pool.pool_value();
+        assert_eq!(
+            pool.check_invariants().unwrap_err(),
+            vec![
+                InvariantViolation::LpSupplyExceedsTvl {
+                    lp_supply: 100 * DEFAULT_PRECISION_FACTOR,
+                    tvl
+                },
+                InvariantViolation::RebalanceThresholdOutOfRange { threshold: 0.5 },
+            ]
+        );
+        assert!(tvl > 0);
+    }
+
+    #[test]
+    fn test_raw_api_is_bit_exact() {
+        let run = || {
+            let mut pool = setup_pool();
+            let lp_minted = pool.add_liquidity_raw(100_000_000).unwrap();
+            let tokens_out = pool.swap_raw(6_000_000).unwrap();
+            let second_lp_minted = pool.add_liquidity_raw(10_000_000).unwrap();
+            let second_tokens_out = pool.swap_raw(30_000_000).unwrap();
+            let removed = pool
+                .remove_liquidity_raw(lp_minted + second_lp_minted)
+                .unwrap();
+            (
+                lp_minted,
+                tokens_out,
+                second_lp_minted,
+                second_tokens_out,
+                removed,
+            )
+        };
+
+        let results = run();
+        assert_eq!(results, run());
+        assert_eq!(
+            results,
+            (
+                99_999_000,
+                8_991_000,
+                9_999_100,
+                43_442_370,
+                (57_566_106, 35_999_672)
+            )
+        );
+
+        let mut pool = setup_pool();
+        assert!(matches!(
+            pool.add_liquidity_raw(0),
+            Err(LpPoolError::InvalidTokenAmount)
+        ));
+        assert!(matches!(
+            pool.remove_liquidity_raw(0),
+            Err(LpPoolError::InvalidTokenAmount)
+        ));
+    }
+
+    #[test]
+    fn test_fee_models() {
+        for fee_model in [
+            FeeModel::Linear,
+            FeeModel::exponential(0.5),
+            FeeModel::exponential(3.0),
+        ] {
+            let pool = LpPool::init(
+                1.5,
+                90.0,
+                0.1,
+                9.0,
+                fee_model,
+                0.0,
+                DEFAULT_PRECISION_FACTOR,
+            )
+            .unwrap();
+            let target = pool.liquidity_target.0;
+            assert_eq!(pool.fee_for_amount_after(0), pool.max_fee.0);
+            assert_eq!(pool.fee_for_amount_after(target), pool.min_fee.0);
+            assert_eq!(pool.fee_for_amount_after(target * 2), pool.min_fee.0);
+
+            let fees: Vec<u64> = (0..=90)
+                .map(|tokens| pool.fee_for_amount_after(tokens * DEFAULT_PRECISION_FACTOR))
+                .collect();
+            assert!(fees.windows(2).all(|pair| pair[0] >= pair[1]));
+
+            // The inverse recovers the reserve of a fee
+            let fee = pool.fee_for_amount_after(30 * DEFAULT_PRECISION_FACTOR) as f64 / 1e6;
+            assert!((pool.amount_after_for_fee(fee) - 30.0).abs() < 1e-3);
+        }
+
+        // The convex curve charges less than the linear one close to the target
+        let linear = LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::Linear,
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        let convex = LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::exponential(3.0),
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        let amount_after = 80 * DEFAULT_PRECISION_FACTOR;
+        assert!(
+            convex.fee_for_amount_after(amount_after) < linear.fee_for_amount_after(amount_after)
+        );
+
+        assert!(LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::exponential(0.0),
+            0.0,
+            DEFAULT_PRECISION_FACTOR
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_protocol_fee_split() {
+        let mut pool = LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::Linear,
+            20.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        pool.add_liquidity(100.0).unwrap();
+
+        // The 0.009 tokens fee of the first story swap
+        assert_eq!(pool.swap(6.0, 0.0).unwrap(), 8.991);
+        assert_eq!(pool.protocol_fee_accrued.0, 1_800);
+        assert_eq!(pool.cumulative_fee_token.0, 7_200);
+        assert_eq!(pool.token_amount.0, 100_000_000 - 8_991_000 - 1_800);
+
+        let estimate = pool.estimate_swap(30.0).unwrap();
+        let fee_tokens = (45.0 - estimate.tokens_out) * DEFAULT_PRECISION_FACTOR as f64;
+        pool.swap(30.0, 0.0).unwrap();
+        let protocol_fee = pool.protocol_fee_accrued.0 - 1_800;
+        assert!((protocol_fee as f64 - fee_tokens * 0.2).abs() <= 1.0);
+
+        let collected = pool.collect_protocol_fees().unwrap();
+        assert_eq!(
+            collected,
+            (1_800 + protocol_fee) as f64 / DEFAULT_PRECISION_FACTOR as f64
+        );
+        assert_eq!(pool.protocol_fee_accrued.0, 0);
+        assert_eq!(pool.collect_protocol_fees().unwrap(), 0.0);
+
+        assert!(LpPool::init(
+            1.5,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::Linear,
+            120.0,
+            DEFAULT_PRECISION_FACTOR
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_minimum_liquidity_lock() {
+        let mut pool = setup_pool();
+        // a minimal pool, the first provider holds every issued LP token
+        let lp_received = pool.add_liquidity_raw(10_000).unwrap();
+        assert_eq!(lp_received, 10_000 - MINIMUM_LIQUIDITY);
+        assert_eq!(pool.locked_lp_tokens.0, MINIMUM_LIQUIDITY);
+        assert_eq!(pool.lp_token_amount.0, 10_000);
+
+        assert!(matches!(
+            pool.remove_liquidity_raw(10_000),
+            Err(LpPoolError::InsufficientLiquidity)
+        ));
+        let (tokens_out, staked_out) = pool.remove_liquidity_raw(lp_received).unwrap();
+        assert_eq!((tokens_out, staked_out), (9_000, 0));
+        assert_eq!(pool.token_amount.0, MINIMUM_LIQUIDITY);
+        assert_eq!(pool.lp_token_amount.0, MINIMUM_LIQUIDITY);
+        assert!(matches!(
+            pool.remove_liquidity_raw(1),
+            Err(LpPoolError::InsufficientLiquidity)
+        ));
+
+        // the first deposit has to cover the locked LP tokens
+        let mut pool = setup_pool();
+        assert!(matches!(
+            pool.add_liquidity_raw(MINIMUM_LIQUIDITY),
+            Err(LpPoolError::InvalidTokenAmount)
+        ));
+    }
+
+    #[test]
+    fn test_update_liquidity_target() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let (token_amount, st_token_amount, lp_token_amount) = (
+            pool.token_amount.0,
+            pool.st_token_amount.0,
+            pool.lp_token_amount.0,
+        );
+        let fee_at_90 = pool.estimate_swap(30.0).unwrap().fee_rate;
+
+        // A higher target puts the swap further below it
+        pool.update_liquidity_target(200.0).unwrap();
+        assert_eq!(pool.liquidity_target.0, 200 * DEFAULT_PRECISION_FACTOR);
+        let fee_at_200 = pool.estimate_swap(30.0).unwrap().fee_rate;
+        assert!(fee_at_200 > fee_at_90 + 0.02);
+
+        // A lower target leaves the swap above it at the minimum fee
+        pool.update_liquidity_target(10.0).unwrap();
+        let fee_at_10 = pool.estimate_swap(30.0).unwrap().fee_rate;
+        assert_eq!(fee_at_10, 0.001);
+        assert_eq!(pool.swap(6.0, 0.0).unwrap(), 8.991);
+
+        assert!(matches!(
+            pool.update_liquidity_target(0.0),
+            Err(LpPoolError::InvalidTokenAmount)
+        ));
+        assert!(pool.update_liquidity_target(-5.0).is_err());
+        assert!(pool.update_liquidity_target(f64::NAN).is_err());
+        assert_eq!(pool.liquidity_target.0, 10 * DEFAULT_PRECISION_FACTOR);
+
+        assert_eq!(pool.token_amount.0, token_amount - 8_991_000);
+        assert_eq!(
+            pool.st_token_amount.0,
+            st_token_amount + 6 * DEFAULT_PRECISION_FACTOR
+        );
+        assert_eq!(pool.lp_token_amount.0, lp_token_amount);
+    }
+
+    #[test]
+    fn test_batch_swap() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let mut sequential_pool = pool.clone();
+        let expected = [
+            sequential_pool.swap(6.0, 0.0).unwrap(),
+            sequential_pool.swap(20.0, 0.0).unwrap(),
+        ];
+
+        assert_eq!(pool.batch_swap(&[6.0, 20.0], 0.0).unwrap(), expected);
+        assert_eq!(pool.token_amount.0, sequential_pool.token_amount.0);
+        assert_eq!(pool.st_token_amount.0, sequential_pool.st_token_amount.0);
+        assert_eq!(
+            pool.cumulative_fee_token.0,
+            sequential_pool.cumulative_fee_token.0
+        );
+    }
+
+    #[test]
+    fn test_batch_swap_reverts_on_slippage() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let before = pool.serialize_to_account_data();
+        let history_len = pool.swap_history.len();
+
+        let estimate = pool.estimate_swap(10.0).unwrap().tokens_out;
+        assert!(matches!(
+            pool.batch_swap(&[5.0, 5.0], estimate + 1.0),
+            Err(LpPoolError::SlippageExceeded { .. })
+        ));
+        assert_eq!(pool.serialize_to_account_data(), before);
+        assert_eq!(pool.swap_history.len(), history_len);
+
+        // A swap failing mid-batch reverts the ones before it
+        assert!(pool.batch_swap(&[6.0, -1.0], 0.0).is_err());
+        assert_eq!(pool.serialize_to_account_data(), before);
+        assert_eq!(pool.swap_history.len(), history_len);
+    }
+
+    #[test]
+    fn test_pause_and_unpause() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.pause();
+
+        assert!(matches!(
+            pool.add_liquidity(10.0),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.add_liquidity_single_sided(1.0),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.remove_liquidity(10.0),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(pool.swap(6.0, 0.0), Err(LpPoolError::PoolPaused)));
+        assert!(matches!(
+            pool.swap_raw(6_000_000),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.swap_tokens_for_staked(1.0, 0.0),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.batch_swap(&[1.0], 0.0),
+            Err(LpPoolError::PoolPaused)
+        ));
+
+        // Admin operations are halted as well
+        let paused_pool = pool.clone();
+        assert!(matches!(pool.rebalance(), Err(LpPoolError::PoolPaused)));
+        assert!(matches!(
+            pool.update_price(2.0),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.apply_price_oracle_update(2.0, 0.0, 0.01),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.update_liquidity_target(10.0),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.set_rebalance_threshold(0.1),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.set_max_price_deviation(100),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.with_fees_disabled(),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.collect_protocol_fees(),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert!(matches!(
+            pool.reset_to_initial(),
+            Err(LpPoolError::PoolPaused)
+        ));
+        assert_eq!(
+            pool.serialize_to_account_data(),
+            paused_pool.serialize_to_account_data()
+        );
+        assert_eq!(pool.token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(LpPoolError::PoolPaused.to_string(), "Pool is paused.");
+
+        // Read-only queries still work
+        assert_eq!(pool.estimate_swap(6.0).unwrap().tokens_out, 8.991);
+        assert_eq!(pool.get_stats().tvl_tokens, 100.0);
+
+        // The paused flag survives a round trip through account data
+        let restored = LpPool::from_account_data(&pool.serialize_to_account_data()).unwrap();
+        assert!(restored.paused);
+
+        pool.unpause();
+        assert_eq!(pool.swap(6.0, 0.0).unwrap(), 8.991);
+        assert!(pool.add_liquidity(10.0).is_ok());
+        assert!(pool.remove_liquidity(10.0).is_ok());
+    }
+
+    #[test]
+    fn test_cumulative_volume_and_fees() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let stats = pool.get_stats();
+        assert_eq!((stats.cumulative_volume, stats.cumulative_fees), (0.0, 0.0));
+
+        let mut expected_volume = 0;
+        let mut expected_fees = 0;
+        let mut last_totals = (0, 0);
+        for staked_raw in [6_000_000, 20_000_000, 10_000_000] {
+            let tokens_out = pool.swap_raw(staked_raw).unwrap();
+            let gross = staked_raw * pool.price.0 / DEFAULT_PRECISION_FACTOR;
+            expected_volume += gross;
+            expected_fees += gross - tokens_out;
+
+            let totals = (pool.total_volume_swapped, pool.total_fees_collected);
+            assert!(totals.0 > last_totals.0 && totals.1 > last_totals.1);
+            assert_eq!(totals, (expected_volume, expected_fees));
+            last_totals = totals;
+        }
+
+        // The reverse direction counts the tokens paid in
+        pool.swap_tokens_for_staked(3.0, 0.0).unwrap();
+        assert_eq!(
+            pool.total_volume_swapped,
+            expected_volume + 3 * DEFAULT_PRECISION_FACTOR
+        );
+        assert!(pool.total_fees_collected > expected_fees);
+
+        let stats = pool.get_stats();
+        assert_eq!(
+            stats.cumulative_volume,
+            pool.total_volume_swapped as f64 / DEFAULT_PRECISION_FACTOR as f64
+        );
+        assert_eq!(
+            stats.cumulative_fees,
+            pool.total_fees_collected as f64 / DEFAULT_PRECISION_FACTOR as f64
+        );
+    }
+
+    #[test]
+    fn test_pool_precision_factor() {
+        let init = |precision_factor: u64| {
+            LpPool::init(1.5, 90.0, 0.1, 9.0, FeeModel::Linear, 0.0, precision_factor)
+        };
+        let mut fine_pool = init(1 << 20).unwrap();
+        let mut coarse_pool = init(1 << 10).unwrap();
+        for pool in [&mut fine_pool, &mut coarse_pool] {
+            pool.add_liquidity(100.0).unwrap();
+            pool.swap(6.0, 0.0).unwrap();
+        }
+
+        let ratio = |fine: u64, coarse: u64| fine as f64 / coarse as f64;
+        assert_eq!(ratio(fine_pool.price.0, coarse_pool.price.0), 1024.0);
+        assert_eq!(
+            ratio(fine_pool.liquidity_target.0, coarse_pool.liquidity_target.0),
+            1024.0
+        );
+        assert_eq!(
+            ratio(fine_pool.lp_token_amount.0, coarse_pool.lp_token_amount.0),
+            1024.0
+        );
+        assert_eq!(
+            ratio(fine_pool.st_token_amount.0, coarse_pool.st_token_amount.0),
+            1024.0
+        );
+        // The coarse pool rounds the fee to 1/1024
+        let token_ratio = ratio(fine_pool.token_amount.0, coarse_pool.token_amount.0);
+        assert!((token_ratio - 1024.0).abs() < 0.1);
+        assert!(
+            (fine_pool.get_stats().tvl_tokens - coarse_pool.get_stats().tvl_tokens).abs() < 1e-3
+        );
+
+        assert!(init(DEFAULT_PRECISION_FACTOR).is_ok());
+        assert!(init(256).is_ok());
+        for precision_factor in [0, 1, 128, 100, 1_000, 3_000, 10_000, 1_000_001, 10_000_000] {
+            assert!(matches!(
+                init(precision_factor),
+                Err(LpPoolError::InvalidConfig)
+            ));
+        }
+
+        let restored = LpPool::from_account_data(&coarse_pool.serialize_to_account_data()).unwrap();
+        assert_eq!(restored.precision_factor, 1 << 10);
+        assert_eq!(restored.token_amount.0, coarse_pool.token_amount.0);
+
+        // A stored factor is validated like one passed to init
+        let mut invalid = coarse_pool.serialize_to_account_data();
+        invalid[160..168].copy_from_slice(&1_000_u64.to_le_bytes());
+        assert!(matches!(
+            LpPool::from_account_data(&invalid),
+            Err(LpPoolError::InvalidConfig)
+        ));
+    }
+
+    #[test]
+    fn test_flash_loan() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+
+        let mut lent = 0.0;
+        pool.flash_loan(50.0, 0.0009, |amount| {
+            lent = amount;
+            Ok(amount * 1.0009)
+        })
+        .unwrap();
+        assert_eq!(lent, 50.0);
+        assert_eq!(pool.token_amount.0, 100 * DEFAULT_PRECISION_FACTOR);
+        assert_eq!(pool.protocol_fee_accrued.0, 45_000);
+
+        // Paying back more than due leaves the excess in the reserve
+        pool.flash_loan(10.0, 0.0, |amount| Ok(amount + 1.0))
+            .unwrap();
+        assert_eq!(pool.token_amount.0, 101 * DEFAULT_PRECISION_FACTOR);
+
+        assert!(matches!(
+            pool.flash_loan(200.0, 0.0009, Ok),
+            Err(LpPoolError::InsufficientLiquidity)
+        ));
+    }
+
+    #[test]
+    fn test_flash_loan_insufficient_repayment() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let before = pool.serialize_to_account_data();
+
+        // The principal without the fee
+        assert!(matches!(
+            pool.flash_loan(50.0, 0.0009, Ok),
+            Err(LpPoolError::InsufficientLiquidity)
+        ));
+        assert_eq!(pool.serialize_to_account_data(), before);
+
+        assert!(matches!(
+            pool.flash_loan(50.0, 0.0009, |_| Err("trade failed".into())),
+            Err(LpPoolError::InsufficientLiquidity)
+        ));
+        assert_eq!(pool.serialize_to_account_data(), before);
+    }
+
+    #[test]
+    fn test_flash_loan_callback_panics() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        let before = pool.serialize_to_account_data();
+
+        let result = pool.flash_loan(50.0, 0.0009, |_| panic!("callback panicked"));
+        assert!(matches!(result, Err(LpPoolError::InsufficientLiquidity)));
+        assert_eq!(pool.serialize_to_account_data(), before);
+        assert!(pool.swap(6.0, 0.0).is_ok());
+    }
+
+    #[test]
+    fn test_raw_newtype_arithmetic() {
+        assert_eq!(TokenAmount(5) + TokenAmount(3), TokenAmount(8));
+        assert_eq!(
+            StakedTokenAmount(5) - StakedTokenAmount(3),
+            StakedTokenAmount(2)
+        );
+        assert_eq!(LpTokenAmount(5) * 3, LpTokenAmount(15));
+        assert_eq!(Price(1_500_000) / 2, Price(750_000));
+        assert!(Percentage(1_000) < Percentage(90_000));
+
+        assert_eq!(
+            TokenAmount(u64::MAX).saturating_add(TokenAmount(1)),
+            TokenAmount(u64::MAX)
+        );
+        assert_eq!(Percentage(1).saturating_sub(Percentage(2)), Percentage(0));
+
+        assert_eq!(
+            TokenAmount(5).checked_add(TokenAmount(3)),
+            Some(TokenAmount(8))
+        );
+        assert_eq!(TokenAmount(u64::MAX).checked_add(TokenAmount(1)), None);
+        assert_eq!(LpTokenAmount(3).checked_sub(LpTokenAmount(5)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to add TokenAmounts with overflow")]
+    fn test_raw_newtype_add_overflow() {
+        let _ = TokenAmount(u64::MAX) + TokenAmount(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to subtract StakedTokenAmounts with overflow")]
+    fn test_raw_newtype_sub_overflow() {
+        let _ = StakedTokenAmount(1) - StakedTokenAmount(2);
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to multiply LpTokenAmount with overflow")]
+    fn test_raw_newtype_mul_overflow() {
+        let _ = LpTokenAmount(u64::MAX) * 2;
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to divide Price by zero")]
+    fn test_raw_newtype_div_by_zero() {
+        let _ = Price(1) / 0;
+    }
+
+    #[test]
+    fn test_update_price() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+        let (token_amount, st_token_amount) = (pool.token_amount, pool.st_token_amount);
+
+        // The 6 staked tokens in the pool became worth 0.3 tokens more
+        let impact = pool.update_price(1.55).unwrap();
+        assert_eq!(impact.old_price, 1.5);
+        assert_eq!(impact.new_price, 1.55);
+        assert!((impact.implied_arb_opportunity - 0.3).abs() < 1e-9);
+        assert_eq!(pool.price, Price(1_550_000));
+        assert_eq!(pool.token_amount, token_amount);
+        assert_eq!(pool.st_token_amount, st_token_amount);
+
+        // A drop lets the token reserve be bought with cheaper staked tokens
+        let impact = pool.update_price(1.24).unwrap();
+        let token_reserve = token_amount.0 as f64 / DEFAULT_PRECISION_FACTOR as f64;
+        assert!((impact.implied_arb_opportunity - token_reserve * 0.2).abs() < 1e-9);
+
+        // The next swap executes at the new price
+        let mut reference_pool = LpPool::init(
+            1.24,
+            90.0,
+            0.1,
+            9.0,
+            FeeModel::Linear,
+            0.0,
+            DEFAULT_PRECISION_FACTOR,
+        )
+        .unwrap();
+        reference_pool.token_amount = pool.token_amount;
+        reference_pool.st_token_amount = pool.st_token_amount;
+        reference_pool.lp_token_amount = pool.lp_token_amount;
+        assert_eq!(
+            pool.swap(10.0, 0.0).unwrap(),
+            reference_pool.swap(10.0, 0.0).unwrap()
+        );
+
+        assert!(matches!(
+            pool.update_price(0.0),
+            Err(LpPoolError::InvalidPrice)
+        ));
+        assert!(pool.update_price(f64::NAN).is_err());
+        assert_eq!(pool.price, Price(1_240_000));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut pool = setup_pool();
+        pool.add_liquidity(100.0).unwrap();
+        pool.swap(6.0, 0.0).unwrap();
+
+        let output = pool.to_string();
+        assert!(output.starts_with("LpPool State\n"));
+        assert!(output.contains("  Price:             1.500000\n"));
+        assert!(output.contains("  Token Reserve:    91.009000\n"));
+        assert!(output.contains("  Staked Reserve:    6.000000\n"));
+        assert!(output.contains("  LP Supply:       100.000000\n"));
+        assert!(output.contains("  Liquidity Target: 90.000000\n"));
+        assert!(output.contains("  Fee Range:         0.10% \u{2013} 9.00%"));
     }
 }
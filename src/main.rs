@@ -1,3 +1 @@
-mod liquidity_pool;
-
 pub fn main() {}
@@ -0,0 +1,75 @@
+/// Fixed-capacity buffer overwriting its oldest element once full.
+#[derive(Clone)]
+pub struct RingBuffer<T, const N: usize> {
+    items: [Option<T>; N],
+    next: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    /// Creates an empty ring buffer.
+    pub fn new() -> Self {
+        RingBuffer {
+            items: std::array::from_fn(|_| None),
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends an item, overwriting the oldest one if the buffer is full.
+    pub fn push(&mut self, item: T) {
+        self.items[self.next] = Some(item);
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// Returns the number of stored items.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the buffer holds no items.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the most recently pushed item.
+    pub fn last(&self) -> Option<&T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.items[(self.next + N - 1) % N].as_ref()
+    }
+
+    /// Iterates over the stored items from the oldest to the newest.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let start = (self.next + N - self.len) % N;
+        (0..self.len).filter_map(move |i| self.items[(start + i) % N].as_ref())
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize, const N: usize> serde::Serialize for RingBuffer<T, N> {
+    /// Serializes the stored items as a sequence from the oldest to the newest.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>, const N: usize> serde::Deserialize<'de> for RingBuffer<T, N> {
+    /// Deserializes a sequence of items, keeping the newest `N`.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut buffer = RingBuffer::new();
+        for item in Vec::<T>::deserialize(deserializer)? {
+            buffer.push(item);
+        }
+        Ok(buffer)
+    }
+}